@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+
+use log::warn;
+use toml::{Table, Value};
+use xkbcommon::xkb::{self, Keysym, KEYSYM_CASE_INSENSITIVE, KEYSYM_NO_FLAGS};
+
+use crate::keyboard::Modifiers;
+
+/// Something the user can bind keys and buttons to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    Commit,
+    Undo,
+    Redo,
+    Clear,
+    NextCandidate,
+    PreviousCandidate,
+    /// Zero-based, named from 1 in the config.
+    SelectCandidate(usize),
+    /// Turns handwriting off and back on.
+    ToggleMode,
+}
+
+impl Action {
+    pub const NAMES: &'static [&'static str] = &[
+        "commit",
+        "undo",
+        "redo",
+        "clear",
+        "next-candidate",
+        "previous-candidate",
+        "select-candidate-<n>",
+        "toggle-mode",
+    ];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        let action = match name {
+            "commit" => Self::Commit,
+            "undo" => Self::Undo,
+            "redo" => Self::Redo,
+            "clear" => Self::Clear,
+            "next-candidate" => Self::NextCandidate,
+            "previous-candidate" => Self::PreviousCandidate,
+            "toggle-mode" => Self::ToggleMode,
+            _ => {
+                let n: usize = name.strip_prefix("select-candidate-")?.parse().ok()?;
+                Self::SelectCandidate(n.checked_sub(1)?)
+            }
+        };
+        Some(action)
+    }
+}
+
+const BTN_STYLUS: u32 = 0x14b;
+const BTN_STYLUS2: u32 = 0x14c;
+const BTN_STYLUS3: u32 = 0x149;
+
+/// Maps keys, stylus buttons and pad buttons to actions.
+pub struct Bindings {
+    keys: HashMap<(Modifiers, Keysym), Action>,
+    /// By evdev button code.
+    stylus: HashMap<u32, Action>,
+    /// By pad button index.
+    pad: HashMap<u32, Action>,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        let mut keys = HashMap::from([
+            ((Modifiers::NONE, Keysym::z), Action::Undo),
+            ((Modifiers::CTRL, Keysym::z), Action::Undo),
+            ((Modifiers::CTRL_SHIFT, Keysym::z), Action::Redo),
+            ((Modifiers::CTRL, Keysym::y), Action::Redo),
+            ((Modifiers::NONE, Keysym::Return), Action::Commit),
+            ((Modifiers::NONE, Keysym::KP_Enter), Action::Commit),
+            ((Modifiers::CTRL_ALT, Keysym::h), Action::ToggleMode),
+        ]);
+        let mut pad = HashMap::new();
+        for i in 0..9 {
+            let digit = Keysym::new(Keysym::_1.raw() + i as u32);
+            keys.insert((Modifiers::NONE, digit), Action::SelectCandidate(i));
+            pad.insert(i as u32, Action::SelectCandidate(i));
+        }
+        Self {
+            keys,
+            stylus: HashMap::from([
+                (BTN_STYLUS, Action::Commit),
+                (BTN_STYLUS2, Action::Undo),
+                (BTN_STYLUS3, Action::Redo),
+            ]),
+            pad,
+        }
+    }
+}
+
+impl Bindings {
+    pub fn key(&self, modifiers: Modifiers, sym: Keysym) -> Option<Action> {
+        self.keys.get(&(modifiers, sym)).copied()
+    }
+
+    pub fn stylus(&self, button: u32) -> Option<Action> {
+        self.stylus.get(&button).copied()
+    }
+
+    pub fn pad(&self, button: u32) -> Option<Action> {
+        self.pad.get(&button).copied()
+    }
+
+    /// Applies the `bindings` section of the config on top of the defaults.
+    /// Binding to `"none"` removes a default binding.
+    pub fn apply(&mut self, table: &Table) -> Result<(), String> {
+        for (kind, value) in table {
+            let Some(table) = value.as_table() else {
+                return Err(format!("bindings.{kind} should be a table"));
+            };
+            for (trigger, action) in table {
+                let name = format!("bindings.{kind}.{trigger:?}");
+                let action = parse_action(&name, action)?;
+                match kind.as_str() {
+                    "keys" => {
+                        let key = parse_key(trigger).ok_or(format!("{name}: unknown key"))?;
+                        update(&mut self.keys, key, action);
+                    }
+                    "stylus" => {
+                        let button = parse_stylus_button(trigger)
+                            .ok_or(format!("{name}: unknown button"))?;
+                        update(&mut self.stylus, button, action);
+                    }
+                    "pad" => {
+                        let button = trigger
+                            .parse()
+                            .map_err(|_| format!("{name}: pad buttons are numbered from 0"))?;
+                        update(&mut self.pad, button, action);
+                    }
+                    _ => {
+                        warn!("unknown binding kind bindings.{kind}");
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn update<K: std::hash::Hash + Eq>(map: &mut HashMap<K, Action>, key: K, action: Option<Action>) {
+    match action {
+        Some(action) => map.insert(key, action),
+        None => map.remove(&key),
+    };
+}
+
+fn parse_action(name: &str, value: &Value) -> Result<Option<Action>, String> {
+    match value.as_str() {
+        Some("none") => Ok(None),
+        Some(action) => Action::parse(action).map(Some).ok_or(format!(
+            "{name}: unknown action {action:?}, available: {}",
+            Action::NAMES.join(", ")
+        )),
+        None => Err(format!("{name} should be the name of an action")),
+    }
+}
+
+/// Parses e.g. `z`, `ctrl+z` or `shift+Return`.
+fn parse_key(s: &str) -> Option<(Modifiers, Keysym)> {
+    let mut parts: Vec<&str> = s.split('+').collect();
+    let key = parts.pop()?;
+    let mut modifiers = Modifiers::NONE;
+    for m in parts {
+        match m.to_lowercase().as_str() {
+            "ctrl" | "control" => modifiers.ctrl = true,
+            "alt" => modifiers.alt = true,
+            "shift" => modifiers.shift = true,
+            "super" | "logo" => modifiers.logo = true,
+            _ => return None,
+        }
+    }
+    let mut sym = xkb::keysym_from_name(key, KEYSYM_NO_FLAGS);
+    if sym.raw() == xkb::keysyms::KEY_NoSymbol {
+        sym = xkb::keysym_from_name(key, KEYSYM_CASE_INSENSITIVE);
+    }
+    (sym.raw() != xkb::keysyms::KEY_NoSymbol).then_some((modifiers, sym))
+}
+
+fn parse_stylus_button(s: &str) -> Option<u32> {
+    match s {
+        "BTN_STYLUS" => Some(BTN_STYLUS),
+        "BTN_STYLUS2" => Some(BTN_STYLUS2),
+        "BTN_STYLUS3" => Some(BTN_STYLUS3),
+        _ => s.parse().ok(),
+    }
+}
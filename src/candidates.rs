@@ -0,0 +1,98 @@
+use crate::config::UiConfig;
+use crate::recognition::Candidate;
+
+const PADDING: f64 = 6.;
+
+/// The ranked candidates of the latest recognition, drawn as a strip of
+/// numbered labels.
+#[derive(Default)]
+pub struct CandidateList {
+    candidates: Vec<Candidate>,
+    selected: usize,
+    /// Horizontal extent of each label as drawn the last time.
+    labels: Vec<(f64, f64)>,
+}
+
+impl CandidateList {
+    pub fn set(&mut self, candidates: Vec<Candidate>) {
+        self.candidates = candidates;
+        self.selected = 0;
+        self.labels.clear();
+    }
+
+    pub fn clear(&mut self) {
+        self.set(vec![]);
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    pub fn selected_text(&self) -> Option<&str> {
+        self.candidates.get(self.selected).map(|c| c.text.as_str())
+    }
+
+    /// Returns false if there is no candidate `index`.
+    pub fn select(&mut self, index: usize) -> bool {
+        if index < self.candidates.len() {
+            self.selected = index;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The candidate whose label covers `x`.
+    pub fn hit(&self, x: f64) -> Option<usize> {
+        self.labels
+            .iter()
+            .position(|&(start, end)| (start..end).contains(&x))
+    }
+
+    /// Draws the strip into the band `y..y + height` of `ctx`.
+    pub fn draw(&mut self, ctx: &cairo::Context, y: f64, width: f64, height: f64, ui: &UiConfig) {
+        ctx.save().unwrap();
+        ctx.rectangle(0., y, width, height);
+        ctx.clip();
+        ui.candidate_background_color.set_source(ctx);
+        ctx.paint().unwrap();
+
+        ctx.select_font_face(
+            "sans-serif",
+            cairo::FontSlant::Normal,
+            cairo::FontWeight::Normal,
+        );
+        ctx.set_font_size(height * 0.6);
+        let baseline = y + height * 0.75;
+        let mut x = 0.;
+        self.labels.clear();
+        for (i, candidate) in self.candidates.iter().enumerate() {
+            let label = if i < 9 {
+                format!("{} {}", i + 1, candidate.text)
+            } else {
+                candidate.text.clone()
+            };
+            let extents = ctx.text_extents(&label).unwrap();
+            let end = x + extents.x_advance() + 2. * PADDING;
+            if i == self.selected {
+                ui.candidate_highlight_color.set_source(ctx);
+                ctx.rectangle(x, y, end - x, height);
+                ctx.fill().unwrap();
+            }
+            ui.ink_color.set_source(ctx);
+            ctx.move_to(x + PADDING, baseline);
+            ctx.show_text(&label).unwrap();
+            self.labels.push((x, end));
+            x = end;
+        }
+        ctx.restore().unwrap();
+    }
+}
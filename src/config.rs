@@ -0,0 +1,522 @@
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use toml::{Table, Value};
+
+use crate::action::Bindings;
+use crate::input::{self, BTN_LEFT};
+use crate::NAME;
+
+const USAGE: &str = "\
+Usage: htrime [OPTIONS]
+
+Options:
+  -c, --config <PATH>      read the configuration from PATH instead of
+                           $XDG_CONFIG_HOME/htrime/config.toml
+  -s, --set <KEY=VALUE>    override a configuration value, e.g.
+                           --set ui.width=300 or --set recognizer.name=simplehtr
+  -w, --wait               if another input method is running, wait for it to
+                           exit instead of exiting, same as
+                           --set input_method.wait=true
+  -h, --help               print this help
+
+Signals:
+  SIGUSR1  turn handwriting off or back on
+
+Exit status:
+  1  the recognizer failed, or there is no memory for the surface
+  2  the configuration is invalid
+  3  another input method is running";
+
+pub struct Config {
+    pub recognizer: RecognizerConfig,
+    pub input_method: InputMethodConfig,
+    pub ui: UiConfig,
+    pub gestures: GestureConfig,
+    pub mouse: MouseConfig,
+    pub touch: TouchConfig,
+    pub text: TextConfig,
+    pub bindings: Bindings,
+}
+
+pub struct RecognizerConfig {
+    pub name: String,
+    pub command: Vec<String>,
+    pub working_directory: Option<PathBuf>,
+    pub input: InkFormat,
+}
+
+pub struct InputMethodConfig {
+    /// Whether to wait for another input method to go away instead of
+    /// exiting.
+    pub wait: bool,
+    /// How often to check whether it has, in seconds.
+    pub retry_interval: u64,
+}
+
+/// What engines speaking the protocol are sent. `Auto` prefers strokes if the
+/// engine accepts them.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InkFormat {
+    Auto,
+    Image,
+    Strokes,
+}
+
+/// How the writing area is presented.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UiMode {
+    /// An input method popup, which the compositor puts next to the text
+    /// cursor.
+    Popup,
+    /// A panel along the bottom edge of the screen.
+    Panel,
+    /// A pad floating above the bottom edge of the screen.
+    Floating,
+}
+
+pub struct UiConfig {
+    pub mode: UiMode,
+    /// Size of the panel or floating pad, including the candidates. The
+    /// panel always spans the whole width of the screen.
+    pub panel_width: i32,
+    pub panel_height: i32,
+    /// Whether windows are kept out from under the panel.
+    pub panel_exclusive: bool,
+    pub width: i32,
+    pub height: i32,
+    pub line_width: f64,
+    /// Diameter of the area the eraser end of a pen clears.
+    pub eraser_width: f64,
+    /// Shows where the tip of a hovering pen is.
+    pub hover_cursor: bool,
+    pub grow_step: i32,
+    pub ink_color: Color,
+    pub background_color: Color,
+    pub candidate_height: i32,
+    /// The writing area is at least this many times as high as the caret. 0
+    /// turns this off.
+    pub caret_scale: f64,
+    pub candidate_background_color: Color,
+    pub candidate_highlight_color: Color,
+}
+
+/// Which strokes are taken as commands instead of ink.
+pub struct GestureConfig {
+    /// Zig-zagging over ink deletes it.
+    pub scratch_out: bool,
+    /// A quick line to the right commits with a space, one to the left is
+    /// backspace.
+    pub flicks: bool,
+    /// A line down and to the left commits with a newline.
+    pub newline: bool,
+    /// Holding the pen still commits.
+    pub hold_to_commit: bool,
+    /// In milliseconds.
+    pub hold_time: u64,
+}
+
+pub struct MouseConfig {
+    /// Writing with the mouse or touchpad.
+    pub enabled: bool,
+    /// The evdev code of the button that writes.
+    pub button: u32,
+}
+
+pub struct TouchConfig {
+    /// Writing with a finger on touchscreens.
+    pub enabled: bool,
+    /// Contacts larger than this, in surface coordinates, are palms.
+    pub palm_size: f64,
+}
+
+/// How recognized text is fitted to the text around the cursor, for text
+/// fields that report it.
+pub struct TextConfig {
+    pub auto_space: bool,
+    pub auto_capitalize: bool,
+}
+
+#[derive(Clone, Copy)]
+pub struct Color {
+    pub r: f64,
+    pub g: f64,
+    pub b: f64,
+    pub a: f64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            recognizer: RecognizerConfig {
+                name: String::from("simplehtr"),
+                command: vec![String::from("python"), String::from("adaptor.py")],
+                working_directory: None,
+                input: InkFormat::Auto,
+            },
+            input_method: InputMethodConfig {
+                wait: false,
+                retry_interval: 2,
+            },
+            ui: UiConfig {
+                mode: UiMode::Popup,
+                panel_width: 800,
+                panel_height: 240,
+                panel_exclusive: false,
+                width: 200,
+                height: 80,
+                line_width: 4.,
+                eraser_width: 16.,
+                hover_cursor: true,
+                grow_step: 100,
+                ink_color: Color::rgba(0., 0., 0., 1.),
+                background_color: Color::rgba(1., 1., 1., 1.),
+                candidate_height: 24,
+                caret_scale: 0.,
+                candidate_background_color: Color::rgba(0.93, 0.93, 0.93, 1.),
+                candidate_highlight_color: Color::rgba(0.75, 0.85, 1., 1.),
+            },
+            gestures: GestureConfig {
+                scratch_out: true,
+                flicks: true,
+                newline: true,
+                hold_to_commit: true,
+                hold_time: 600,
+            },
+            mouse: MouseConfig {
+                enabled: true,
+                button: BTN_LEFT,
+            },
+            touch: TouchConfig {
+                enabled: true,
+                palm_size: 80.,
+            },
+            text: TextConfig {
+                auto_space: true,
+                auto_capitalize: true,
+            },
+            bindings: Bindings::default(),
+        }
+    }
+}
+
+impl Color {
+    pub const fn rgba(r: f64, g: f64, b: f64, a: f64) -> Self {
+        Self { r, g, b, a }
+    }
+
+    /// Parses `#rrggbb` or `#rrggbbaa`.
+    fn parse(s: &str) -> Option<Self> {
+        let hex = s.strip_prefix('#')?;
+        if !(hex.len() == 6 || hex.len() == 8) || !hex.is_ascii() {
+            return None;
+        }
+        let channel = |i: usize| {
+            hex.get(i..i + 2)
+                .and_then(|c| u8::from_str_radix(c, 16).ok())
+                .map(|c| c as f64 / 255.)
+        };
+        let a = if hex.len() == 8 { channel(6)? } else { 1. };
+        Some(Self::rgba(channel(0)?, channel(2)?, channel(4)?, a))
+    }
+
+    pub fn set_source(&self, ctx: &cairo::Context) {
+        ctx.set_source_rgba(self.r, self.g, self.b, self.a);
+    }
+}
+
+/// Loads the configuration file and applies the command line overrides.
+pub fn load() -> Result<Config, String> {
+    let mut path = None;
+    let mut overrides = vec![];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => {
+                path = Some(PathBuf::from(
+                    args.next().ok_or(format!("{arg} needs a value"))?,
+                ));
+            }
+            "-s" | "--set" => overrides.push(args.next().ok_or(format!("{arg} needs a value"))?),
+            "-w" | "--wait" => overrides.push(String::from("input_method.wait=true")),
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                std::process::exit(0);
+            }
+            _ => return Err(format!("unknown argument {arg:?}\n\n{USAGE}")),
+        }
+    }
+
+    let mut table = match path {
+        Some(path) => read_table(&path)?,
+        None => match default_path() {
+            Some(path) if path.exists() => read_table(&path)?,
+            _ => Table::new(),
+        },
+    };
+    for o in overrides {
+        apply_override(&mut table, &o)?;
+    }
+    Config::from_table(&table)
+}
+
+fn default_path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_home.join(NAME).join("config.toml"))
+}
+
+fn read_table(path: &Path) -> Result<Table, String> {
+    info!("loading config from {}", path.display());
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+    content
+        .parse::<Table>()
+        .map_err(|e| format!("failed to parse {}: {e}", path.display()))
+}
+
+/// Applies `section.key=value`, where the value is parsed as a TOML value if
+/// possible and taken as a string otherwise.
+fn apply_override(table: &mut Table, o: &str) -> Result<(), String> {
+    let (key, value) = o
+        .split_once('=')
+        .ok_or(format!("override {o:?} is not of the form KEY=VALUE"))?;
+    let value = format!("v = {value}")
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+        .unwrap_or_else(|| Value::String(value.to_string()));
+    let mut path: Vec<&str> = key.trim().split('.').collect();
+    let last = path.pop().unwrap();
+    let mut table = table;
+    for section in path {
+        table = table
+            .entry(section)
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .ok_or(format!("{section} in {key:?} is not a table"))?;
+    }
+    table.insert(last.to_string(), value);
+    Ok(())
+}
+
+impl Config {
+    fn from_table(table: &Table) -> Result<Self, String> {
+        let mut config = Self::default();
+        for (section, value) in table {
+            let Some(values) = value.as_table() else {
+                return Err(format!("{section} should be a table"));
+            };
+            match section.as_str() {
+                "recognizer" => config.recognizer.apply(values)?,
+                "input_method" => config.input_method.apply(values)?,
+                "ui" => config.ui.apply(values)?,
+                "gestures" => config.gestures.apply(values)?,
+                "mouse" => config.mouse.apply(values)?,
+                "touch" => config.touch.apply(values)?,
+                "text" => config.text.apply(values)?,
+                "bindings" => config.bindings.apply(values)?,
+                _ => warn!("unknown config section {section:?}"),
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl RecognizerConfig {
+    fn apply(&mut self, table: &Table) -> Result<(), String> {
+        for (key, value) in table {
+            let name = format!("recognizer.{key}");
+            match key.as_str() {
+                "name" => self.name = string(&name, value)?,
+                "command" => {
+                    self.command = match value {
+                        Value::String(s) => s.split_whitespace().map(String::from).collect(),
+                        Value::Array(a) => a
+                            .iter()
+                            .map(|v| string(&name, v))
+                            .collect::<Result<_, _>>()?,
+                        _ => return Err(format!("{name} should be a string or an array")),
+                    };
+                    if self.command.is_empty() {
+                        return Err(format!("{name} is empty"));
+                    }
+                }
+                "working_directory" => {
+                    self.working_directory = Some(PathBuf::from(string(&name, value)?))
+                }
+                "input" => {
+                    self.input = match string(&name, value)?.as_str() {
+                        "auto" => InkFormat::Auto,
+                        "image" => InkFormat::Image,
+                        "strokes" => InkFormat::Strokes,
+                        _ => return Err(format!("{name} should be auto, image or strokes")),
+                    }
+                }
+                _ => warn!("unknown config option {name:?}"),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl UiConfig {
+    fn apply(&mut self, table: &Table) -> Result<(), String> {
+        for (key, value) in table {
+            let name = format!("ui.{key}");
+            match key.as_str() {
+                "mode" => {
+                    self.mode = match string(&name, value)?.as_str() {
+                        "popup" => UiMode::Popup,
+                        "panel" => UiMode::Panel,
+                        "floating" => UiMode::Floating,
+                        _ => return Err(format!("{name} should be popup, panel or floating")),
+                    }
+                }
+                "panel_width" => self.panel_width = size(&name, value)?,
+                "panel_height" => self.panel_height = size(&name, value)?,
+                "panel_exclusive" => self.panel_exclusive = boolean(&name, value)?,
+                "width" => self.width = size(&name, value)?,
+                "height" => self.height = size(&name, value)?,
+                "line_width" => self.line_width = float(&name, value)?,
+                "eraser_width" => self.eraser_width = float(&name, value)?,
+                "hover_cursor" => self.hover_cursor = boolean(&name, value)?,
+                "grow_step" => self.grow_step = size(&name, value)?,
+                "ink_color" => self.ink_color = color(&name, value)?,
+                "background_color" => self.background_color = color(&name, value)?,
+                "candidate_height" => self.candidate_height = size(&name, value)?,
+                "caret_scale" => self.caret_scale = float(&name, value)?,
+                "candidate_background_color" => {
+                    self.candidate_background_color = color(&name, value)?
+                }
+                "candidate_highlight_color" => {
+                    self.candidate_highlight_color = color(&name, value)?
+                }
+                _ => warn!("unknown config option {name:?}"),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl InputMethodConfig {
+    fn apply(&mut self, table: &Table) -> Result<(), String> {
+        for (key, value) in table {
+            let name = format!("input_method.{key}");
+            match key.as_str() {
+                "wait" => self.wait = boolean(&name, value)?,
+                "retry_interval" => self.retry_interval = positive(&name, value)? as u64,
+                _ => warn!("unknown config option {name:?}"),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl GestureConfig {
+    fn apply(&mut self, table: &Table) -> Result<(), String> {
+        for (key, value) in table {
+            let name = format!("gestures.{key}");
+            match key.as_str() {
+                "scratch_out" => self.scratch_out = boolean(&name, value)?,
+                "flicks" => self.flicks = boolean(&name, value)?,
+                "newline" => self.newline = boolean(&name, value)?,
+                "hold_to_commit" => self.hold_to_commit = boolean(&name, value)?,
+                "hold_time" => self.hold_time = positive(&name, value)? as u64,
+                _ => warn!("unknown config option {name:?}"),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MouseConfig {
+    fn apply(&mut self, table: &Table) -> Result<(), String> {
+        for (key, value) in table {
+            let name = format!("mouse.{key}");
+            match key.as_str() {
+                "enabled" => self.enabled = boolean(&name, value)?,
+                "button" => {
+                    self.button = input::parse_mouse_button(&string(&name, value)?)
+                        .ok_or(format!("{name}: unknown button"))?
+                }
+                _ => warn!("unknown config option {name:?}"),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TouchConfig {
+    fn apply(&mut self, table: &Table) -> Result<(), String> {
+        for (key, value) in table {
+            let name = format!("touch.{key}");
+            match key.as_str() {
+                "enabled" => self.enabled = boolean(&name, value)?,
+                "palm_size" => self.palm_size = float(&name, value)?,
+                _ => warn!("unknown config option {name:?}"),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TextConfig {
+    fn apply(&mut self, table: &Table) -> Result<(), String> {
+        for (key, value) in table {
+            let name = format!("text.{key}");
+            match key.as_str() {
+                "auto_space" => self.auto_space = boolean(&name, value)?,
+                "auto_capitalize" => self.auto_capitalize = boolean(&name, value)?,
+                _ => warn!("unknown config option {name:?}"),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn string(key: &str, value: &Value) -> Result<String, String> {
+    value
+        .as_str()
+        .map(String::from)
+        .ok_or(format!("{key} should be a string"))
+}
+
+fn boolean(key: &str, value: &Value) -> Result<bool, String> {
+    value
+        .as_bool()
+        .ok_or(format!("{key} should be true or false"))
+}
+
+fn positive(key: &str, value: &Value) -> Result<i32, String> {
+    value
+        .as_integer()
+        .filter(|&i| i > 0)
+        .and_then(|i| i32::try_from(i).ok())
+        .ok_or(format!("{key} should be a positive integer"))
+}
+
+/// The largest size in pixels that a surface can be configured to, so that
+/// its buffers stay well within what can be allocated.
+const MAX_SIZE: i32 = 4096;
+
+fn size(key: &str, value: &Value) -> Result<i32, String> {
+    Some(positive(key, value)?)
+        .filter(|&size| size <= MAX_SIZE)
+        .ok_or(format!("{key} should be at most {MAX_SIZE}"))
+}
+
+fn float(key: &str, value: &Value) -> Result<f64, String> {
+    value
+        .as_float()
+        .or_else(|| value.as_integer().map(|i| i as f64))
+        .ok_or(format!("{key} should be a number"))
+}
+
+fn color(key: &str, value: &Value) -> Result<Color, String> {
+    value.as_str().and_then(Color::parse).ok_or(format!(
+        "{key} should be a color like \"#rrggbb\" or \"#rrggbbaa\""
+    ))
+}
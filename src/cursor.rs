@@ -0,0 +1,116 @@
+use std::f64::consts::TAU;
+use std::io;
+
+use wayland_client::protocol::wl_compositor::WlCompositor;
+use wayland_client::protocol::wl_shm::WlShm;
+use wayland_client::protocol::wl_surface::WlSurface;
+use wayland_client::QueueHandle;
+
+use crate::config::{Color, UiConfig};
+use crate::shm::Buffers;
+use crate::{create_canvas, State};
+
+/// Width of the light outline that keeps cursors visible over ink.
+const OUTLINE: f64 = 1.5;
+
+#[derive(Clone, Copy)]
+pub enum Shape {
+    /// Where the ink will go.
+    Dot,
+    /// What the eraser will hit.
+    Ring,
+}
+
+/// The cursors for the two ends of a pen.
+pub struct Cursors {
+    pub pen: Cursor,
+    pub eraser: Cursor,
+}
+
+impl Cursors {
+    pub fn new(
+        compositor: &WlCompositor,
+        shm: &WlShm,
+        qh: &QueueHandle<State>,
+        ui: &UiConfig,
+        scale: i32,
+    ) -> io::Result<Self> {
+        let cursor = |shape, diameter| {
+            Cursor::new(compositor, shm, qh, shape, diameter, ui.ink_color, scale)
+        };
+        Ok(Self {
+            pen: cursor(Shape::Dot, ui.line_width.max(2.))?,
+            // The eraser hits strokes whose edge it touches.
+            eraser: cursor(Shape::Ring, ui.eraser_width + ui.line_width)?,
+        })
+    }
+}
+
+/// A cursor surface for tablet tools, showing where the tip is while the
+/// tool hovers.
+pub struct Cursor {
+    pub surface: WlSurface,
+    /// The buffer attached to the surface lives in here.
+    _buffers: Buffers,
+    /// The center, in surface coordinates.
+    pub hotspot: i32,
+}
+
+impl Cursor {
+    pub fn new(
+        compositor: &WlCompositor,
+        shm: &WlShm,
+        qh: &QueueHandle<State>,
+        shape: Shape,
+        diameter: f64,
+        color: Color,
+        scale: i32,
+    ) -> io::Result<Self> {
+        // Even, so that the center is on a pixel corner the hotspot can be
+        // set to. The ring's outline is the widest part.
+        let size = 2 * ((diameter / 2. + 2. * OUTLINE).ceil() as i32 + 1);
+        let hotspot = size / 2;
+        let (canvas, image) = create_canvas(size * scale, size * scale);
+        let ctx = cairo::Context::new(&image).unwrap();
+        ctx.scale(scale as f64, scale as f64);
+        let center = hotspot as f64;
+        ctx.arc(center, center, diameter / 2., 0., TAU);
+        match shape {
+            Shape::Dot => {
+                color.set_source(&ctx);
+                ctx.fill_preserve().unwrap();
+                ctx.set_source_rgba(1., 1., 1., 0.8);
+                ctx.set_line_width(OUTLINE);
+                ctx.stroke().unwrap();
+            }
+            Shape::Ring => {
+                ctx.set_source_rgba(1., 1., 1., 0.8);
+                ctx.set_line_width(3. * OUTLINE);
+                ctx.stroke_preserve().unwrap();
+                color.set_source(&ctx);
+                ctx.set_line_width(OUTLINE);
+                ctx.stroke().unwrap();
+            }
+        }
+        drop(ctx);
+        image.flush();
+        let data =
+            unsafe { std::slice::from_raw_parts(canvas.as_ptr() as *const u8, canvas.len() * 4) };
+        let mut buffers = Buffers::new(shm, size * scale, size * scale, qh)?;
+        let surface = compositor.create_surface(qh, ());
+        surface.set_buffer_scale(scale);
+        surface.attach(buffers.next(data, qh)?, 0, 0);
+        surface.commit();
+        Ok(Self {
+            surface,
+            _buffers: buffers,
+            hotspot,
+        })
+    }
+}
+
+impl Drop for Cursor {
+    fn drop(&mut self) {
+        self.surface.destroy();
+    }
+}
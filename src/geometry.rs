@@ -0,0 +1,46 @@
+pub type Point = (f64, f64);
+
+/// The top left and bottom right corners of the bounding box.
+pub fn bounds(points: &[Point]) -> Option<(Point, Point)> {
+    let first = *points.first()?;
+    Some(points.iter().fold((first, first), |(min, max), p| {
+        (
+            (min.0.min(p.0), min.1.min(p.1)),
+            (max.0.max(p.0), max.1.max(p.1)),
+        )
+    }))
+}
+
+/// The shortest distance between the segments `a0..a1` and `b0..b1`.
+pub fn segment_distance(a0: Point, a1: Point, b0: Point, b1: Point) -> f64 {
+    if segments_intersect(a0, a1, b0, b1) {
+        return 0.;
+    }
+    point_segment_distance(a0, b0, b1)
+        .min(point_segment_distance(a1, b0, b1))
+        .min(point_segment_distance(b0, a0, a1))
+        .min(point_segment_distance(b1, a0, a1))
+}
+
+pub fn point_segment_distance(p: Point, a: Point, b: Point) -> f64 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let length_squared = dx * dx + dy * dy;
+    let t = if length_squared == 0. {
+        0.
+    } else {
+        (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / length_squared).clamp(0., 1.)
+    };
+    let (x, y) = (a.0 + t * dx, a.1 + t * dy);
+    (p.0 - x).hypot(p.1 - y)
+}
+
+/// Whether the segments properly cross. Touching and collinear segments are
+/// left to the distance check.
+fn segments_intersect(a0: Point, a1: Point, b0: Point, b1: Point) -> bool {
+    let side = |p: Point, q: Point, r: Point| (q.0 - p.0) * (r.1 - p.1) - (q.1 - p.1) * (r.0 - p.0);
+    let d1 = side(b0, b1, a0);
+    let d2 = side(b0, b1, a1);
+    let d3 = side(a0, a1, b0);
+    let d4 = side(a0, a1, b1);
+    d1 * d2 < 0. && d3 * d4 < 0.
+}
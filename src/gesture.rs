@@ -0,0 +1,273 @@
+use std::time::Duration;
+
+use crate::geometry::{bounds, point_segment_distance, Point};
+use crate::Stroke;
+
+/// A stroke that is meant as a command rather than as ink.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Gesture {
+    /// A zig-zag over existing ink, which deletes it.
+    ScratchOut,
+    /// A quick straight line to the right.
+    FlickRight,
+    /// A quick straight line to the left.
+    FlickLeft,
+    /// A line down and then to the left, like the return key.
+    Hook,
+    /// Holding the pen still.
+    Hold,
+}
+
+/// What strokes are measured against.
+pub struct Limits {
+    /// Used to tell real changes of direction from jitter.
+    pub line_width: f64,
+    /// Height of the writing area. Flicks and hooks have to be long compared
+    /// to it, so that dashes are still ink.
+    pub height: f64,
+    pub hold_time: Duration,
+}
+
+/// How many times a scratch-out has to change direction.
+const SCRATCH_REVERSALS: usize = 3;
+/// How much longer than its bounding box diagonal a scratch-out has to be.
+const SCRATCH_DENSITY: f64 = 2.5;
+/// Flicks and hooks are at least this long relative to the writing area.
+const FLICK_LENGTH: f64 = 0.5;
+const FLICK_TIME: Duration = Duration::from_millis(300);
+/// How much longer than the distance between its ends a line may be.
+const STRAIGHTNESS: f64 = 1.15;
+/// The foot of a hook is at least this long relative to the line down, so
+/// that a J stays ink.
+const HOOK_FOOT: f64 = 0.75;
+
+/// Classifies a finished stroke that took `duration` from pen down to pen up.
+pub fn recognize(stroke: &Stroke, duration: Duration, limits: &Limits) -> Option<Gesture> {
+    let points: Vec<Point> = stroke.points.iter().map(|p| (p.x, p.y)).collect();
+    if duration >= limits.hold_time && extent(&points) <= 2. * limits.line_width {
+        return Some(Gesture::Hold);
+    }
+    if is_scratch_out(&points, limits.line_width) {
+        return Some(Gesture::ScratchOut);
+    }
+    let min_length = FLICK_LENGTH * limits.height;
+    if is_hook(&points, min_length) {
+        return Some(Gesture::Hook);
+    }
+    if duration <= FLICK_TIME {
+        if let Some(dx) = horizontal_line(&points, min_length) {
+            return Some(if dx > 0. {
+                Gesture::FlickRight
+            } else {
+                Gesture::FlickLeft
+            });
+        }
+    }
+    None
+}
+
+fn is_scratch_out(points: &[Point], line_width: f64) -> bool {
+    let diagonal = extent(points);
+    if diagonal < 4. * line_width {
+        return false;
+    }
+    if length(points) < SCRATCH_DENSITY * diagonal {
+        return false;
+    }
+    // Scratching is usually sideways, but up and down works too.
+    let tolerance = (2. * line_width).max(0.1 * diagonal);
+    let horizontal = reversals(points.iter().map(|p| p.0), tolerance);
+    let vertical = reversals(points.iter().map(|p| p.1), tolerance);
+    horizontal.max(vertical) >= SCRATCH_REVERSALS
+}
+
+/// Counts the changes of direction along one axis, ignoring movements back
+/// by less than `tolerance`.
+fn reversals(values: impl Iterator<Item = f64>, tolerance: f64) -> usize {
+    let mut count = 0;
+    let mut direction = 0.;
+    let mut extreme: Option<f64> = None;
+    for v in values {
+        let Some(e) = extreme else {
+            extreme = Some(v);
+            continue;
+        };
+        let delta = v - e;
+        if direction == 0. {
+            if delta.abs() >= tolerance {
+                direction = delta.signum();
+                extreme = Some(v);
+            }
+        } else if delta * direction > 0. {
+            extreme = Some(v);
+        } else if -delta * direction >= tolerance {
+            count += 1;
+            direction = -direction;
+            extreme = Some(v);
+        }
+    }
+    count
+}
+
+/// The horizontal displacement of a straight, mostly horizontal line of at
+/// least `min_length`.
+fn horizontal_line(points: &[Point], min_length: f64) -> Option<f64> {
+    let (first, last) = (*points.first()?, *points.last()?);
+    let (dx, dy) = (last.0 - first.0, last.1 - first.1);
+    (dx.abs() >= min_length && dy.abs() <= 0.3 * dx.abs() && is_straight(points)).then_some(dx)
+}
+
+/// A line going down by at least `min_length` and then left by at least half
+/// of that and `HOOK_FOOT` of the way down.
+fn is_hook(points: &[Point], min_length: f64) -> bool {
+    let (Some(&first), Some(&last)) = (points.first(), points.last()) else {
+        return false;
+    };
+    // The corner is the point furthest from the line between the ends.
+    let Some(corner) = (0..points.len()).max_by(|&a, &b| {
+        let distance = |i: usize| point_segment_distance(points[i], first, last);
+        distance(a).total_cmp(&distance(b))
+    }) else {
+        return false;
+    };
+    let (down, left) = (&points[..=corner], &points[corner..]);
+    let corner = points[corner];
+    let (down_x, down_y) = (corner.0 - first.0, corner.1 - first.1);
+    let (left_x, left_y) = (last.0 - corner.0, last.1 - corner.1);
+    down_y >= min_length
+        && down_x.abs() <= 0.5 * down_y
+        && -left_x >= (min_length / 2.).max(HOOK_FOOT * down_y)
+        && left_y.abs() <= 0.5 * -left_x
+        && is_straight(down)
+        && is_straight(left)
+}
+
+fn is_straight(points: &[Point]) -> bool {
+    let (Some(&first), Some(&last)) = (points.first(), points.last()) else {
+        return false;
+    };
+    let chord = (last.0 - first.0).hypot(last.1 - first.1);
+    chord > 0. && length(points) <= STRAIGHTNESS * chord
+}
+
+fn length(points: &[Point]) -> f64 {
+    points
+        .windows(2)
+        .map(|w| (w[1].0 - w[0].0).hypot(w[1].1 - w[0].1))
+        .sum()
+}
+
+/// The diagonal of the bounding box, 0 for no points.
+fn extent(points: &[Point]) -> f64 {
+    bounds(points).map_or(0., |(min, max)| (max.0 - min.0).hypot(max.1 - min.1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::Device;
+    use crate::InkPoint;
+
+    const LIMITS: Limits = Limits {
+        line_width: 3.,
+        height: 100.,
+        hold_time: Duration::from_millis(500),
+    };
+
+    /// A pen stroke through `corners`, with points every few pixels in
+    /// between like a real tablet gives.
+    fn stroke(corners: &[Point]) -> Stroke {
+        let mut points = vec![corners[0]];
+        for w in corners.windows(2) {
+            let steps = 10;
+            for i in 1..=steps {
+                let t = i as f64 / steps as f64;
+                points.push((
+                    w[0].0 + t * (w[1].0 - w[0].0),
+                    w[0].1 + t * (w[1].1 - w[0].1),
+                ));
+            }
+        }
+        Stroke {
+            points: points
+                .into_iter()
+                .map(|(x, y)| InkPoint {
+                    x,
+                    y,
+                    time: 0,
+                    pressure: None,
+                    tilt: None,
+                    rotation: None,
+                    distance: None,
+                })
+                .collect(),
+            device: Device::Pen,
+        }
+    }
+
+    fn recognize_ms(corners: &[Point], duration: u64) -> Option<Gesture> {
+        recognize(&stroke(corners), Duration::from_millis(duration), &LIMITS)
+    }
+
+    #[test]
+    fn scratch_out() {
+        let sideways = [(0., 0.), (40., 3.), (0., 6.), (40., 9.), (0., 12.)];
+        assert_eq!(recognize_ms(&sideways, 400), Some(Gesture::ScratchOut));
+        let upright = [(0., 0.), (3., 40.), (6., 0.), (9., 40.), (12., 0.)];
+        assert_eq!(recognize_ms(&upright, 400), Some(Gesture::ScratchOut));
+    }
+
+    #[test]
+    fn flicks() {
+        let right = [(0., 50.), (80., 55.)];
+        assert_eq!(recognize_ms(&right, 150), Some(Gesture::FlickRight));
+        let left = [(80., 50.), (0., 45.)];
+        assert_eq!(recognize_ms(&left, 150), Some(Gesture::FlickLeft));
+    }
+
+    #[test]
+    fn hook() {
+        let hook = [(50., 10.), (52., 70.), (0., 72.)];
+        assert_eq!(recognize_ms(&hook, 400), Some(Gesture::Hook));
+    }
+
+    #[test]
+    fn hold() {
+        let still = [(50., 50.), (51., 50.), (50., 51.), (51., 51.)];
+        assert_eq!(recognize_ms(&still, 600), Some(Gesture::Hold));
+    }
+
+    #[test]
+    fn letters_stay_ink() {
+        // N: two changes of direction are not enough for a scratch-out.
+        let n = [(0., 40.), (0., 0.), (30., 40.), (30., 0.)];
+        assert_eq!(recognize_ms(&n, 400), None);
+        // L goes right after going down.
+        let l = [(10., 10.), (10., 70.), (50., 70.)];
+        assert_eq!(recognize_ms(&l, 400), None);
+        // U is not straight enough for a flick.
+        let u = [(0., 0.), (40., 40.), (80., 0.)];
+        assert_eq!(recognize_ms(&u, 150), None);
+        // A dot, written quickly.
+        let dot = [(50., 50.), (51., 51.)];
+        assert_eq!(recognize_ms(&dot, 100), None);
+    }
+
+    #[test]
+    fn lines_stay_ink() {
+        // A dash is too short for a flick.
+        let dash = [(0., 50.), (30., 50.)];
+        assert_eq!(recognize_ms(&dash, 100), None);
+        // An underline is written too slowly.
+        let underline = [(0., 50.), (80., 50.)];
+        assert_eq!(recognize_ms(&underline, 600), None);
+        // A slash is too steep.
+        let slash = [(0., 60.), (60., 0.)];
+        assert_eq!(recognize_ms(&slash, 150), None);
+        // A hook with a short foot is a J, even when written quickly.
+        let j = [(50., 10.), (50., 70.), (35., 70.)];
+        assert_eq!(recognize_ms(&j, 400), None);
+        let j = [(50., 10.), (50., 70.), (20., 70.)];
+        assert_eq!(recognize_ms(&j, 150), None);
+    }
+}
@@ -0,0 +1,97 @@
+use crate::Stroke;
+
+/// A reversible change to the ink. Undoing an edit turns it into its inverse,
+/// which is what redoing it applies.
+pub enum Edit {
+    Strokes {
+        /// Indices of the strokes this edit put into the ink, ascending.
+        inserted: Vec<usize>,
+        /// Strokes this edit took out of the ink with their former indices,
+        /// ascending.
+        removed: Vec<(usize, Stroke)>,
+    },
+    SelectCandidate {
+        from: usize,
+        to: usize,
+    },
+}
+
+impl Edit {
+    pub fn add_stroke(index: usize) -> Self {
+        Self::Strokes {
+            inserted: vec![index],
+            removed: vec![],
+        }
+    }
+
+    /// Takes the strokes at `indices` out of `strokes` and records them.
+    pub fn remove_strokes(strokes: &mut Vec<Stroke>, indices: &[usize]) -> Self {
+        Self::Strokes {
+            inserted: vec![],
+            removed: take(strokes, indices),
+        }
+    }
+
+    /// Reverts the edit and returns its inverse.
+    fn revert(self, strokes: &mut Vec<Stroke>) -> Self {
+        match self {
+            Self::Strokes { inserted, removed } => {
+                let taken = take(strokes, &inserted);
+                let mut reinserted = Vec::with_capacity(removed.len());
+                for (index, stroke) in removed {
+                    let index = index.min(strokes.len());
+                    strokes.insert(index, stroke);
+                    reinserted.push(index);
+                }
+                Self::Strokes {
+                    inserted: reinserted,
+                    removed: taken,
+                }
+            }
+            Self::SelectCandidate { from, to } => Self::SelectCandidate { from: to, to: from },
+        }
+    }
+}
+
+fn take(strokes: &mut Vec<Stroke>, indices: &[usize]) -> Vec<(usize, Stroke)> {
+    let mut taken = Vec::with_capacity(indices.len());
+    for &i in indices.iter().rev() {
+        if i < strokes.len() {
+            taken.push((i, strokes.remove(i)));
+        }
+    }
+    taken.reverse();
+    taken
+}
+
+#[derive(Default)]
+pub struct History {
+    undo: Vec<Edit>,
+    redo: Vec<Edit>,
+}
+
+impl History {
+    pub fn push(&mut self, edit: Edit) {
+        self.undo.push(edit);
+        self.redo.clear();
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+
+    /// Reverts the last edit and returns the edit that restored the ink, if
+    /// there was anything to undo.
+    pub fn undo(&mut self, strokes: &mut Vec<Stroke>) -> Option<&Edit> {
+        let inverse = self.undo.pop()?.revert(strokes);
+        self.redo.push(inverse);
+        self.redo.last()
+    }
+
+    pub fn redo(&mut self, strokes: &mut Vec<Stroke>) -> Option<&Edit> {
+        let inverse = self.redo.pop()?.revert(strokes);
+        self.undo.push(inverse);
+        self.undo.last()
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+
+use wayland_client::backend::ObjectId;
+use wayland_protocols::wp::tablet::zv2::client::zwp_tablet_tool_v2::ZwpTabletToolV2;
+
+use crate::geometry::Point;
+
+pub const BTN_LEFT: u32 = 0x110;
+const BTN_RIGHT: u32 = 0x111;
+const BTN_MIDDLE: u32 = 0x112;
+
+/// The kind of device a stroke was written with.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Device {
+    Mouse,
+    Pen,
+    Touch,
+}
+
+/// Something that can touch the surface: the pointer, a tablet tool or a
+/// finger.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Source {
+    Pointer,
+    Tool(ObjectId),
+    /// By touch point id.
+    Touch(i32),
+}
+
+impl Device {
+    pub fn name(self) -> &'static str {
+        match self {
+            Device::Mouse => "mouse",
+            Device::Pen => "pen",
+            Device::Touch => "touch",
+        }
+    }
+}
+
+impl Source {
+    pub fn device(&self) -> Device {
+        match self {
+            Source::Pointer => Device::Mouse,
+            Source::Tool(_) => Device::Pen,
+            Source::Touch(_) => Device::Touch,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum Contact {
+    /// Over the surface without touching it.
+    #[default]
+    Hovering,
+    Drawing,
+    Erasing,
+    /// Touching the surface without writing, such as a palm, a second finger
+    /// or a tap on a candidate.
+    Ignored,
+}
+
+#[derive(Default)]
+pub struct Input {
+    /// In the coordinates of the writing area.
+    pub position: Point,
+    pub pressure: Option<u32>,
+    /// In degrees, along the x and y axes.
+    pub tilt: Option<(f64, f64)>,
+    /// In degrees, clockwise from the tool's neutral position.
+    pub rotation: Option<f64>,
+    /// From 0 to 65535, only while hovering.
+    pub distance: Option<u32>,
+    pub contact: Contact,
+    /// For tablet tools.
+    pub frame: ToolFrame,
+    /// For tablet tools, with the serial of their proximity_in event, to set
+    /// their cursor again.
+    pub tool: Option<(ZwpTabletToolV2, u32)>,
+}
+
+/// The events of a tablet tool since the last `frame` event, which are
+/// handled together at the time it gives. Axes like pressure are stored
+/// right away, since they only take effect with the next point.
+#[derive(Default)]
+pub struct ToolFrame {
+    /// In surface coordinates.
+    pub motion: Option<Point>,
+    pub down: bool,
+    pub up: bool,
+    pub proximity_out: bool,
+}
+
+/// All sources over the surface. Only one of them draws or erases at a time,
+/// the others are ignored until it is lifted.
+#[derive(Default)]
+pub struct Inputs {
+    inputs: HashMap<Source, Input>,
+}
+
+impl Inputs {
+    pub fn get(&mut self, source: &Source) -> &mut Input {
+        self.inputs.entry(source.clone()).or_default()
+    }
+
+    pub fn contact(&self, source: &Source) -> Contact {
+        self.inputs
+            .get(source)
+            .map_or(Contact::Hovering, |input| input.contact)
+    }
+
+    pub fn remove(&mut self, source: &Source) {
+        self.inputs.remove(source);
+    }
+
+    /// The source that is drawing or erasing.
+    pub fn active(&self) -> Option<&Source> {
+        self.inputs
+            .iter()
+            .find(|(_, input)| matches!(input.contact, Contact::Drawing | Contact::Erasing))
+            .map(|(source, _)| source)
+    }
+
+    pub fn is_drawing(&self) -> bool {
+        self.inputs
+            .values()
+            .any(|input| input.contact == Contact::Drawing)
+    }
+
+    /// Whether a source of the given kind is over or on the surface, other
+    /// than `except`.
+    pub fn has(&self, device: Device, except: &Source) -> bool {
+        self.inputs
+            .keys()
+            .any(|source| source != except && source.device() == device)
+    }
+
+    pub fn touches(&self) -> Vec<Source> {
+        self.inputs
+            .keys()
+            .filter(|source| source.device() == Device::Touch)
+            .cloned()
+            .collect()
+    }
+
+    /// The tablet tools over the surface, with the serial of their
+    /// proximity_in event.
+    pub fn tools(&self) -> Vec<(ZwpTabletToolV2, u32)> {
+        self.inputs
+            .values()
+            .filter_map(|input| input.tool.clone())
+            .collect()
+    }
+
+    /// Stops all drawing and erasing, without lifting anything.
+    pub fn ignore_all(&mut self) {
+        for input in self.inputs.values_mut() {
+            if input.contact != Contact::Hovering {
+                input.contact = Contact::Ignored;
+            }
+        }
+    }
+}
+
+pub fn parse_mouse_button(s: &str) -> Option<u32> {
+    match s {
+        "BTN_LEFT" => Some(BTN_LEFT),
+        "BTN_RIGHT" => Some(BTN_RIGHT),
+        "BTN_MIDDLE" => Some(BTN_MIDDLE),
+        _ => s.parse().ok(),
+    }
+}
@@ -0,0 +1,113 @@
+use std::os::fd::OwnedFd;
+
+use xkbcommon::xkb::{
+    self, Keycode, Keymap, Keysym, ModIndex, CONTEXT_NO_FLAGS, KEYMAP_COMPILE_NO_FLAGS,
+    KEYMAP_FORMAT_TEXT_V1, STATE_MODS_EFFECTIVE,
+};
+
+/// The modifiers that take part in key bindings.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default, Debug)]
+pub struct Modifiers {
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+    pub logo: bool,
+}
+
+impl Modifiers {
+    pub const NONE: Self = Self {
+        ctrl: false,
+        alt: false,
+        shift: false,
+        logo: false,
+    };
+
+    pub const CTRL: Self = Self {
+        ctrl: true,
+        ..Self::NONE
+    };
+
+    pub const CTRL_SHIFT: Self = Self {
+        shift: true,
+        ..Self::CTRL
+    };
+
+    pub const CTRL_ALT: Self = Self {
+        alt: true,
+        ..Self::CTRL
+    };
+}
+
+/// Keyboard state as sent by the compositor through the keyboard grab.
+pub struct XkbState {
+    keymap: Keymap,
+    state: xkb::State,
+    ctrl: ModIndex,
+    alt: ModIndex,
+    shift: ModIndex,
+    logo: ModIndex,
+}
+
+impl XkbState {
+    /// # Safety
+    ///
+    /// `fd` must be a keymap file of at least `size` bytes.
+    pub unsafe fn from_fd(fd: OwnedFd, size: u32) -> Option<Self> {
+        let context = xkb::Context::new(CONTEXT_NO_FLAGS);
+        let keymap = Keymap::new_from_fd(
+            &context,
+            fd,
+            size as usize,
+            KEYMAP_FORMAT_TEXT_V1,
+            KEYMAP_COMPILE_NO_FLAGS,
+        )
+        .ok()??;
+        let state = xkb::State::new(&keymap);
+        Some(Self {
+            ctrl: keymap.mod_get_index(xkb::MOD_NAME_CTRL),
+            alt: keymap.mod_get_index(xkb::MOD_NAME_ALT),
+            shift: keymap.mod_get_index(xkb::MOD_NAME_SHIFT),
+            logo: keymap.mod_get_index(xkb::MOD_NAME_LOGO),
+            keymap,
+            state,
+        })
+    }
+
+    /// Applies a `modifiers` event. The compositor reports every change of
+    /// modifiers and layout this way, so keys are not fed to `update_key`.
+    pub fn update_modifiers(&mut self, depressed: u32, latched: u32, locked: u32, group: u32) {
+        self.state
+            .update_mask(depressed, latched, locked, 0, 0, group);
+    }
+
+    pub fn modifiers(&self) -> Modifiers {
+        let active = |idx| self.state.mod_index_is_active(idx, STATE_MODS_EFFECTIVE);
+        Modifiers {
+            ctrl: active(self.ctrl),
+            alt: active(self.alt),
+            shift: active(self.shift),
+            logo: active(self.logo),
+        }
+    }
+
+    /// The symbol the key produces with the current modifiers and layout.
+    pub fn sym(&self, key: u32) -> Keysym {
+        self.state.key_get_one_sym(evdev_to_xkb(key))
+    }
+
+    /// The symbol the key produces in the current layout without any
+    /// modifiers, so that e.g. Shift+z is still seen as z in bindings.
+    pub fn base_sym(&self, key: u32) -> Keysym {
+        let keycode = evdev_to_xkb(key);
+        let layout = self.state.key_get_layout(keycode);
+        self.keymap
+            .key_get_syms_by_level(keycode, layout, 0)
+            .first()
+            .copied()
+            .unwrap_or_else(|| self.sym(key))
+    }
+}
+
+fn evdev_to_xkb(key: u32) -> Keycode {
+    (key + 8).into()
+}
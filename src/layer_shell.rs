@@ -0,0 +1,19 @@
+//! Bindings for `protocols/wlr-layer-shell-unstable-v1.xml`, which is not part
+//! of wayland-protocols.
+
+#![allow(dead_code, non_camel_case_types, unused_unsafe, unused_variables)]
+#![allow(non_upper_case_globals, non_snake_case, unused_imports)]
+#![allow(missing_docs, clippy::all)]
+
+use wayland_client;
+use wayland_client::protocol::*;
+use wayland_protocols::xdg::shell::client::*;
+
+pub mod __interfaces {
+    use wayland_client::protocol::__interfaces::*;
+    use wayland_protocols::xdg::shell::client::__interfaces::*;
+    wayland_scanner::generate_interfaces!("protocols/wlr-layer-shell-unstable-v1.xml");
+}
+use self::__interfaces::*;
+
+wayland_scanner::generate_client_code!("protocols/wlr-layer-shell-unstable-v1.xml");
@@ -1,37 +1,61 @@
+mod action;
+mod candidates;
+mod config;
+mod cursor;
+mod geometry;
+mod gesture;
+mod history;
+mod input;
+mod keyboard;
+mod layer_shell;
 mod recognition;
+mod shm;
+mod text;
 
-use std::ffi::CString;
-use std::io::{BufRead, BufReader, Read, Write};
-use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
-use std::os::raw::c_void;
-use std::process::{Child, ChildStdout};
-use std::ptr::null_mut;
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::os::fd::{AsFd, AsRawFd};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
 
 use libc::{
-    epoll_event, epoll_wait, fcntl, ftruncate, mmap, poll, shm_open, EPOLL_CLOEXEC, EPOLL_CTL_ADD,
-    F_GETFL, F_SETFL, O_CREAT, O_EXCL, O_NONBLOCK, O_RDWR,
+    epoll_event, epoll_wait, fcntl, EPOLL_CLOEXEC, EPOLL_CTL_ADD, F_GETFL, F_SETFL, O_NONBLOCK,
 };
-use wayland_client::protocol::wl_buffer::WlBuffer;
+use wayland_client::backend::ObjectId;
+use wayland_client::protocol::wl_buffer::{self, WlBuffer};
+use wayland_client::protocol::wl_callback::{self, WlCallback};
 use wayland_client::protocol::wl_compositor::WlCompositor;
 use wayland_client::protocol::wl_keyboard::{KeyState, KeymapFormat};
 use wayland_client::protocol::wl_pointer::{ButtonState, WlPointer};
-use wayland_client::protocol::wl_seat::WlSeat;
+use wayland_client::protocol::wl_seat::{self, WlSeat};
 use wayland_client::protocol::wl_shm::WlShm;
 use wayland_client::protocol::wl_shm_pool::WlShmPool;
 use wayland_client::protocol::wl_surface;
 use wayland_client::protocol::wl_surface::WlSurface;
+use wayland_client::protocol::wl_touch::{self, WlTouch};
 use wayland_client::{delegate_noop, event_created_child, EventQueue, Proxy, WEnum};
 use wayland_client::{protocol::wl_registry, Connection, Dispatch, QueueHandle};
 
+use wayland_protocols::wp::fractional_scale::v1::client::wp_fractional_scale_manager_v1::WpFractionalScaleManagerV1;
+use wayland_protocols::wp::fractional_scale::v1::client::wp_fractional_scale_v1::{
+    self, WpFractionalScaleV1,
+};
 use wayland_protocols::wp::tablet::zv2::client::zwp_tablet_manager_v2::ZwpTabletManagerV2;
-use wayland_protocols::wp::tablet::zv2::client::zwp_tablet_pad_v2::ZwpTabletPadV2;
-use wayland_protocols::wp::tablet::zv2::client::zwp_tablet_seat_v2::{
-    self, ZwpTabletSeatV2, EVT_TABLET_ADDED_OPCODE, EVT_TOOL_ADDED_OPCODE,
+use wayland_protocols::wp::tablet::zv2::client::zwp_tablet_pad_group_v2::{
+    ZwpTabletPadGroupV2, EVT_RING_OPCODE, EVT_STRIP_OPCODE,
 };
-use wayland_protocols::wp::tablet::zv2::client::zwp_tablet_tool_v2::{
-    self, ZwpTabletToolV2,
+use wayland_protocols::wp::tablet::zv2::client::zwp_tablet_pad_ring_v2::ZwpTabletPadRingV2;
+use wayland_protocols::wp::tablet::zv2::client::zwp_tablet_pad_strip_v2::ZwpTabletPadStripV2;
+use wayland_protocols::wp::tablet::zv2::client::zwp_tablet_pad_v2::{
+    self, ZwpTabletPadV2, EVT_GROUP_OPCODE,
+};
+use wayland_protocols::wp::tablet::zv2::client::zwp_tablet_seat_v2::{
+    self, ZwpTabletSeatV2, EVT_PAD_ADDED_OPCODE, EVT_TABLET_ADDED_OPCODE, EVT_TOOL_ADDED_OPCODE,
 };
+use wayland_protocols::wp::tablet::zv2::client::zwp_tablet_tool_v2::{self, ZwpTabletToolV2};
 use wayland_protocols::wp::tablet::zv2::client::zwp_tablet_v2::ZwpTabletV2;
+use wayland_protocols::wp::viewporter::client::wp_viewport::WpViewport;
+use wayland_protocols::wp::viewporter::client::wp_viewporter::WpViewporter;
 use wayland_protocols_misc::zwp_input_method_v2::client::zwp_input_method_keyboard_grab_v2::{
     self, ZwpInputMethodKeyboardGrabV2,
 };
@@ -41,30 +65,88 @@ use wayland_protocols_misc::zwp_input_method_v2::client::zwp_input_popup_surface
 use wayland_protocols_misc::zwp_input_method_v2::client::{
     zwp_input_method_v2, zwp_input_popup_surface_v2,
 };
-
-use log::{info, trace, warn};
-
-use xkbcommon::xkb::{Keymap, CONTEXT_NO_FLAGS, KEYMAP_COMPILE_NO_FLAGS, KEYMAP_FORMAT_TEXT_V1};
+use wayland_protocols_misc::zwp_virtual_keyboard_v1::client::zwp_virtual_keyboard_manager_v1::ZwpVirtualKeyboardManagerV1;
+use wayland_protocols_misc::zwp_virtual_keyboard_v1::client::zwp_virtual_keyboard_v1::ZwpVirtualKeyboardV1;
+
+use log::{error, info, trace, warn};
+
+use action::Action;
+use candidates::CandidateList;
+use config::{Color, Config, UiMode};
+use cursor::Cursors;
+use gesture::Gesture;
+use history::{Edit, History};
+use input::{Contact, Device, Inputs, Source};
+use keyboard::XkbState;
+use layer_shell::zwlr_layer_shell_v1::{self, ZwlrLayerShellV1};
+use layer_shell::zwlr_layer_surface_v1::{self, ZwlrLayerSurfaceV1};
+use recognition::{Candidate, Image, Ink, Recognizer, RequestId};
+use text::{Mode, SurroundingText};
 
 const NAME: &str = "htrime";
+/// Exit status when another input method is running.
+const EXIT_UNAVAILABLE: u8 = 3;
+/// Turns handwriting off or back on, also while nothing of htrime is shown.
+const TOGGLE_SIGNAL: i32 = libc::SIGUSR1;
 
 struct Globals {
     input_method_manager: Option<ZwpInputMethodManagerV2>,
+    virtual_keyboard_manager: Option<ZwpVirtualKeyboardManagerV1>,
     tablet_manager: Option<ZwpTabletManagerV2>,
     seat: Option<WlSeat>,
     compositor: Option<WlCompositor>,
     shm: Option<WlShm>,
+    layer_shell: Option<ZwlrLayerShellV1>,
+    viewporter: Option<WpViewporter>,
+    fractional_scale_manager: Option<WpFractionalScaleManagerV1>,
 }
 
 struct State {
     conn: Connection,
     wayland_qh: QueueHandle<Self>,
     shm: WlShm,
-    shm_pool: WlShmPool,
-    pointer: WlPointer,
+    buffers: shm::Buffers,
+    /// What the surface shows, copied into a free buffer for each frame.
+    canvas: Vec<u32>,
+    /// Waiting for the compositor to ask for the next frame.
+    frame_pending: bool,
+    /// The canvas has changed since the last frame.
+    needs_display: bool,
+    touch: Option<WlTouch>,
+    inputs: Inputs,
+    input_method_manager: ZwpInputMethodManagerV2,
+    seat: WlSeat,
     input_method: ZwpInputMethodV2,
+    /// When to ask for the input method again after it was unavailable.
+    input_method_retry: Option<Instant>,
+    /// The status to exit with, checked by the main loop so that it is not
+    /// left from inside an event handler.
+    exit_status: Option<u8>,
     input_method_serial: u32,
-    popup: ZwpInputPopupSurfaceV2,
+    /// Whether a text field is focused and wants input.
+    active: bool,
+    pending: PendingState,
+    /// Only held while there is ink or handwriting is off, so that typing
+    /// works normally otherwise.
+    keyboard_grab: Option<ZwpInputMethodKeyboardGrabV2>,
+    virtual_keyboard: Option<ZwpVirtualKeyboardV1>,
+    /// Keys whose press was handled by htrime, so their release is not
+    /// forwarded either.
+    consumed_keys: HashSet<u32>,
+    compositor: WlCompositor,
+    layer_shell: Option<ZwlrLayerShellV1>,
+    viewporter: Option<WpViewporter>,
+    fractional_scale_manager: Option<WpFractionalScaleManagerV1>,
+    /// Scales the buffer down to the surface, for fractional scales.
+    viewport: Option<(WpViewport, WpFractionalScaleV1)>,
+    /// Buffer pixels per surface coordinate.
+    scale: f64,
+    /// Created when a pen first comes near, at the current scale.
+    cursors: Option<Cursors>,
+    /// Only in popup mode.
+    popup: Option<ZwpInputPopupSurfaceV2>,
+    /// Only in panel and floating mode, while active.
+    panel: Option<Panel>,
     surface: WlSurface,
     cairo_surface: cairo::ImageSurface,
     cairo_ctx: cairo::Context,
@@ -72,64 +154,168 @@ struct State {
     height: i32,
     original_width: i32,
     original_height: i32,
+    /// The text input rectangle relative to the surface, if known.
+    caret: Option<Caret>,
+    /// Where the writing area starts on the surface: 0 with the candidates
+    /// below it, their height with the candidates above it.
+    ink_top: i32,
     strokes: Vec<Stroke>,
-    is_pen_down: bool,
-    pressure: Option<u32>,
-    line_width: f64,
-    buffer: WlBuffer,
-    data_ptr: *mut c_void,
+    history: History,
+    pen_down_at: Instant,
+    /// The type of each tablet tool, by proxy.
+    tools: HashMap<ObjectId, zwp_tablet_tool_v2::Type>,
+    /// Strokes crossed by the eraser so far, hidden until the eraser is lifted
+    /// and they are removed in one edit.
+    erased: HashSet<usize>,
+    /// Where the eraser was at the last motion.
+    eraser_position: (f64, f64),
     xkb_state: Option<XkbState>,
-    recognition: Child,
+    recognition: Box<dyn Recognizer>,
+    recognition_id: RequestId,
+    recognition_pending: bool,
+    /// What to commit after the preedit text once the pending recognition
+    /// is done, if a commit was asked for before it was.
+    pending_commit: Option<String>,
     preedit_text: String,
+    /// `None` if the text field does not report it.
+    surrounding_text: Option<SurroundingText>,
+    mode: Mode,
+    candidates: CandidateList,
+    /// Turned off by the toggle-mode action or `TOGGLE_SIGNAL`, which hides
+    /// the popup.
+    enabled: bool,
     max_x: f64,
     max_y: f64,
-}
-
-struct XkbState {
-    keymap: Keymap,
-    xkb_context: xkbcommon::xkb::Context,
-    state: xkbcommon::xkb::State,
+    config: Config,
 }
 
 impl Globals {
     fn new() -> Self {
         Self {
             input_method_manager: None,
+            virtual_keyboard_manager: None,
             seat: None,
             compositor: None,
             shm: None,
             tablet_manager: None,
+            layer_shell: None,
+            viewporter: None,
+            fractional_scale_manager: None,
         }
     }
 }
 
+/// Distance of the floating pad from the bottom edge of the screen.
+const PANEL_MARGIN: i32 = 16;
+
+/// How much wider than upright a pen draws when lying flat.
+const TILT_WIDTH: f64 = 0.5;
+
+struct Panel {
+    layer_surface: ZwlrLayerSurfaceV1,
+    /// Nothing may be attached before the first configure.
+    configured: bool,
+}
+
+/// Input method state sent by the compositor, which only takes effect on the
+/// next `done` event.
+#[derive(Default)]
+struct PendingState {
+    /// `Some(true)` after `activate`, `Some(false)` after `deactivate`.
+    active: Option<bool>,
+    surrounding_text: Option<SurroundingText>,
+    mode: Option<Mode>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Caret {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+}
+
 struct InkPoint {
     x: f64,
     y: f64,
     time: u32,
     pressure: Option<u32>,
+    /// In degrees, along the x and y axes.
+    tilt: Option<(f64, f64)>,
+    /// In degrees, clockwise.
+    rotation: Option<f64>,
+    /// From 0 to 65535.
+    distance: Option<u32>,
 }
 
 struct Stroke {
     points: Vec<InkPoint>,
+    device: Device,
+}
+
+impl Stroke {
+    /// Whether the stroke comes within `radius` of the segment `from..to`.
+    fn crosses(&self, from: geometry::Point, to: geometry::Point, radius: f64) -> bool {
+        let mut points = self.points.iter().map(|p| (p.x, p.y));
+        let Some(mut prev) = points.next() else {
+            return false;
+        };
+        if geometry::segment_distance(prev, prev, from, to) <= radius {
+            return true;
+        }
+        points.any(|p| {
+            let hit = geometry::segment_distance(prev, p, from, to) <= radius;
+            prev = p;
+            hit
+        })
+    }
 }
 
-fn main() {
+fn main() -> ExitCode {
     env_logger::init();
 
-    let (mut state, mut wayland_queue) = init();
+    let config = match config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{e}");
+            return ExitCode::from(2);
+        }
+    };
+    let recognition = match recognition::spawn(&config.recognizer) {
+        Ok(recognition) => recognition,
+        Err(e) => {
+            error!(
+                "failed to start recognizer {:?}: {e}",
+                config.recognizer.name
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let (mut state, mut wayland_queue) = init(config, recognition);
 
     let epoll_fd = unsafe { libc::epoll_create1(EPOLL_CLOEXEC) };
     assert!(epoll_fd >= 0);
 
-    let (recognition_fd, mut recognition_reader) = epoll_add_recoginition(&mut state, epoll_fd);
-    let mut recognition_output = String::new();
+    let recognition_fd = epoll_add_recoginition(&state, epoll_fd);
 
     let wayland_fd = epoll_add_wayland(&state, epoll_fd);
+    let signal_fd = epoll_add_signal(epoll_fd);
     const MAX_EVENTS: usize = 16;
     let mut events = [epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
 
     loop {
+        if let Some(status) = state.exit_status {
+            state.recognition.shutdown();
+            return ExitCode::from(status);
+        }
+        if state
+            .input_method_retry
+            .is_some_and(|retry| retry <= Instant::now())
+        {
+            state.retry_input_method();
+        }
+
         // flush the outgoing buffers to ensure that the server does receive the messages
         // you've sent
 
@@ -152,6 +338,7 @@ fn main() {
          */
         let mut wayland_socket_ready = false;
         let mut recognition_ready = false;
+        let mut signal_ready = false;
         unsafe {
             let num_event = epoll_wait(epoll_fd, events.as_mut_ptr(), MAX_EVENTS as i32, 1000);
             assert!(num_event >= 0);
@@ -163,6 +350,8 @@ fn main() {
                     wayland_socket_ready = true;
                 } else if e.u64 == recognition_fd as u64 {
                     recognition_ready = true;
+                } else if e.u64 == signal_fd as u64 {
+                    signal_ready = true;
                 }
             }
         }
@@ -182,40 +371,39 @@ fn main() {
         /*
          * There you process all relevant events from your other event sources
          */
+        if signal_ready && read_signals(signal_fd) {
+            state.toggle_mode();
+        }
         if recognition_ready {
-            unsafe {
-                let mut poll_fds = [libc::pollfd {
-                    fd: recognition_fd,
-                    events: libc::POLLIN,
-                    revents: 0,
-                }];
-                let ret = poll(&mut poll_fds as *mut _, 1, 0);
-                if ret == 0 {
-                    panic!("poll timeout");
-                }
-            }
-            if recognition_output.ends_with('\n') {
-                recognition_output.clear();
-            }
-            if let Err(e) = recognition_reader.read_line(&mut recognition_output) {
-                if e.raw_os_error() == Some(libc::EAGAIN) {
-                    warn!("Incomplete line {:?} {e:?}", recognition_output);
-                    continue;
-                } else {
-                    panic!("Failed to read from child: {}", e);
+            let events = match state.recognition.receive() {
+                Ok(Some(events)) => events,
+                Ok(None) => {
+                    error!("recognizer exited");
+                    state.recognition.shutdown();
+                    return ExitCode::FAILURE;
+                }
+                Err(e) => panic!("Failed to read from recognizer: {}", e),
+            };
+            for event in events {
+                match event {
+                    recognition::Event::Recognized { id, .. }
+                        if id != state.recognition_id || !state.recognition_pending =>
+                    {
+                        trace!("dropping stale result of request {id}");
+                    }
+                    recognition::Event::Recognized { candidates, .. } => {
+                        state.recognition_pending = false;
+                        state.on_recognized(candidates);
+                    }
+                    recognition::Event::Error { id, message } => {
+                        warn!("recognition of request {id:?} failed: {message}");
+                        if id == Some(state.recognition_id) && state.recognition_pending {
+                            state.recognition_pending = false;
+                            state.finish_commit();
+                        }
+                    }
                 }
             }
-            trace!("recognition output: {}", recognition_output);
-            let header = "recognized:";
-            if let Some(s) = recognition_output.strip_prefix(header) {
-                state.preedit_text.clear();
-                state.preedit_text.push_str(s.trim());
-                info!("preedit text: {:?}", state.preedit_text);
-            }
-            state
-                .input_method
-                .set_preedit_string(state.preedit_text.clone(), 0, 0);
-            state.input_method.commit(state.input_method_serial);
         }
     }
 }
@@ -231,8 +419,8 @@ fn epoll_add_wayland(state: &State, epoll_fd: i32) -> i32 {
     wayland_fd
 }
 
-fn epoll_add_recoginition(state: &mut State, epoll_fd: i32) -> (i32, BufReader<ChildStdout>) {
-    let recognition_fd = state.recognition.stdout.as_ref().unwrap().as_raw_fd();
+fn epoll_add_recoginition(state: &State, epoll_fd: i32) -> i32 {
+    let recognition_fd = state.recognition.fd();
     let mut recognition_event = libc::epoll_event {
         events: libc::EPOLLIN as u32,
         u64: recognition_fd as u64,
@@ -246,10 +434,41 @@ fn epoll_add_recoginition(state: &mut State, epoll_fd: i32) -> (i32, BufReader<C
         )
     };
     assert!(ret >= 0);
-    let handle = state.recognition.stdout.take().unwrap();
-    set_nonblocking(&handle, true).unwrap();
-    let recognition_reader = BufReader::new(handle);
-    (recognition_fd, recognition_reader)
+    recognition_fd
+}
+
+/// Receives `TOGGLE_SIGNAL` through a file descriptor instead of a handler.
+fn epoll_add_signal(epoll_fd: i32) -> i32 {
+    let signal_fd = unsafe {
+        let mut mask: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut mask);
+        libc::sigaddset(&mut mask, TOGGLE_SIGNAL);
+        let ret = libc::sigprocmask(libc::SIG_BLOCK, &mask, std::ptr::null_mut());
+        assert!(ret == 0);
+        libc::signalfd(-1, &mask, libc::SFD_CLOEXEC | libc::SFD_NONBLOCK)
+    };
+    assert!(signal_fd >= 0);
+    let mut signal_event = libc::epoll_event {
+        events: libc::EPOLLIN as u32,
+        u64: signal_fd as u64,
+    };
+    let ret = unsafe { libc::epoll_ctl(epoll_fd, EPOLL_CTL_ADD, signal_fd, &mut signal_event) };
+    assert!(ret >= 0);
+    signal_fd
+}
+
+/// Reads all pending signals, returning whether there were any.
+fn read_signals(signal_fd: i32) -> bool {
+    let mut info: libc::signalfd_siginfo = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<libc::signalfd_siginfo>();
+    let mut received = false;
+    while unsafe { libc::read(signal_fd, &mut info as *mut _ as *mut libc::c_void, size) }
+        == size as isize
+    {
+        info!("signal {}", info.ssi_signo);
+        received = true;
+    }
+    received
 }
 
 fn set_nonblocking<H>(handle: &H, nonblocking: bool) -> std::io::Result<()>
@@ -273,33 +492,7 @@ where
     Ok(())
 }
 
-fn shm_file(size: i32) -> BorrowedFd<'static> {
-    unsafe {
-        let name = CString::new(NAME).unwrap();
-        let name_ptr = name.as_ptr();
-        let fd = shm_open(name_ptr, O_RDWR | O_CREAT | O_EXCL, 0o600);
-        if fd < 0 {
-            panic!("shm_open failed")
-        }
-        // shm_unlink(name_ptr);
-        loop {
-            let ret = ftruncate(fd, size as i64);
-            if ret < 0 {
-                let error = std::io::Error::last_os_error();
-                if error.kind() == std::io::ErrorKind::Interrupted {
-                    continue;
-                } else {
-                    panic!("ftruncate failed")
-                }
-            } else {
-                break;
-            }
-        }
-        BorrowedFd::borrow_raw(fd)
-    }
-}
-
-fn init() -> (State, EventQueue<State>) {
+fn init(mut config: Config, recognition: Box<dyn Recognizer>) -> (State, EventQueue<State>) {
     let conn = Connection::connect_to_env().unwrap();
     let mut registry_queue: EventQueue<Globals> = conn.new_event_queue();
     let registry_qh = registry_queue.handle();
@@ -320,95 +513,127 @@ fn init() -> (State, EventQueue<State>) {
     tablet_manager.get_tablet_seat(&seat, &wayland_qh, ());
 
     let shm = globals.shm.unwrap();
-    let size = 1024 * 1024 * 1024;
-    let fd = shm_file(size);
-    let shm_pool = shm.create_pool(fd, size, &wayland_qh, ());
-    let width = 200;
-    let height = 80;
-    let stride = width * 4;
-    let buffer_size = stride * height;
-
-    let data_ptr = unsafe {
-        mmap(
-            null_mut::<libc::c_void>(),
-            size as usize,
-            libc::PROT_READ | libc::PROT_WRITE,
-            libc::MAP_SHARED,
-            fd.as_raw_fd(),
-            0,
-        )
+    let width = config.ui.width;
+    let height = config.ui.height;
+    let surface_height = height + config.ui.candidate_height;
+    let buffers = match shm::Buffers::new(&shm, width, surface_height, &wayland_qh) {
+        Ok(buffers) => buffers,
+        Err(e) => {
+            error!("failed to create the shm pool: {e}");
+            std::process::exit(1);
+        }
     };
 
-    let pointer = seat.get_pointer(&wayland_qh, ());
+    // Its events go to the Dispatch impl, nothing is asked of it.
+    seat.get_pointer(&wayland_qh, ());
 
     let surface = compositor.create_surface(&wayland_qh, ());
 
     let input_method = manager.get_input_method(&seat, &wayland_qh, ());
 
-    let popup = input_method.get_input_popup_surface(&surface, &wayland_qh, ());
-
-    let _keyboard_grab = input_method.grab_keyboard(&wayland_qh, ());
-
-    let data: &mut [u8] =
-        unsafe { std::slice::from_raw_parts_mut(data_ptr as *mut u8, buffer_size as usize) };
+    if config.ui.mode != UiMode::Popup && globals.layer_shell.is_none() {
+        warn!("zwlr_layer_shell_v1 is not supported, using a popup instead");
+        config.ui.mode = UiMode::Popup;
+    }
+    let popup = (config.ui.mode == UiMode::Popup)
+        .then(|| input_method.get_input_popup_surface(&surface, &wayland_qh, ()));
+
+    let virtual_keyboard = match globals.virtual_keyboard_manager {
+        Some(manager) => Some(manager.create_virtual_keyboard(&seat, &wayland_qh, ())),
+        None => {
+            warn!("zwp_virtual_keyboard_manager_v1 is not supported, keys pressed while writing are lost");
+            None
+        }
+    };
 
-    let buffer = shm_pool.create_buffer(
-        0,
-        width,
-        height,
-        stride,
-        wayland_client::protocol::wl_shm::Format::Argb8888,
-        &wayland_qh,
-        (),
-    );
-
-    let cairo_surface =
-        cairo::ImageSurface::create_for_data(data, cairo::Format::ARgb32, width, height, stride)
-            .unwrap();
+    let (canvas, cairo_surface) = create_canvas(width, surface_height);
     let ctx = cairo::Context::new(&cairo_surface).unwrap();
-    set_line(&ctx);
-    fill_background(&ctx);
-
-    surface.attach(Some(&buffer), 0, 0);
-    surface.damage(0, 0, i32::MAX, i32::MAX);
-    surface.commit();
+    set_line(&ctx, config.ui.ink_color, config.ui.line_width);
+    fill_background(&ctx, config.ui.background_color);
 
-    let recognition = recognition::run();
     let mut state = State {
         shm,
-        pointer,
+        touch: None,
+        inputs: Inputs::default(),
         input_method,
         surface,
         cairo_surface,
+        compositor,
+        layer_shell: globals.layer_shell,
+        viewporter: globals.viewporter,
+        fractional_scale_manager: globals.fractional_scale_manager,
+        viewport: None,
+        scale: 1.,
+        cursors: None,
         popup,
-        shm_pool,
+        panel: None,
+        buffers,
+        canvas,
+        frame_pending: false,
+        needs_display: false,
         conn,
         strokes: vec![],
-        is_pen_down: false,
+        history: History::default(),
+        pen_down_at: Instant::now(),
+        tools: HashMap::new(),
+        erased: HashSet::new(),
+        eraser_position: (0., 0.),
         cairo_ctx: ctx,
-        buffer,
-        data_ptr,
         width,
         height,
         xkb_state: None,
         recognition,
+        recognition_id: 0,
+        recognition_pending: false,
+        pending_commit: None,
         preedit_text: String::new(),
+        surrounding_text: None,
+        mode: Mode::default(),
+        candidates: CandidateList::default(),
+        enabled: true,
+        input_method_manager: manager,
+        seat,
+        input_method_retry: None,
+        exit_status: None,
         input_method_serial: 0,
-        pressure: None,
-        line_width: 4.,
+        active: false,
+        pending: PendingState::default(),
+        keyboard_grab: None,
+        virtual_keyboard,
+        consumed_keys: HashSet::new(),
         wayland_qh,
         max_x: 0.,
         max_y: 0.,
         original_width: width,
         original_height: height,
+        caret: None,
+        ink_top: 0,
+        config,
     };
+    state.scale_surface();
 
     (state, wayland_queue)
 }
 
-fn fill_background(ctx: &cairo::Context) {
+/// An image surface drawing into memory of its own, which outlives it.
+fn create_canvas(width: i32, height: i32) -> (Vec<u32>, cairo::ImageSurface) {
+    let mut canvas = vec![0; (width * height) as usize];
+    let surface = unsafe {
+        cairo::ImageSurface::create_for_data_unsafe(
+            canvas.as_mut_ptr() as *mut u8,
+            cairo::Format::ARgb32,
+            width,
+            height,
+            width * 4,
+        )
+    }
+    .unwrap();
+    (canvas, surface)
+}
+
+fn fill_background(ctx: &cairo::Context, color: Color) {
     ctx.save().unwrap();
-    ctx.set_source_rgba(1.0, 1.0, 1.0, 1.0);
+    color.set_source(ctx);
     ctx.set_operator(cairo::Operator::Source);
     ctx.paint().unwrap();
     ctx.restore().unwrap();
@@ -427,7 +652,9 @@ impl Dispatch<wl_registry::WlRegistry, QueueHandle<State>> for Globals {
         _: &QueueHandle<Self>,
     ) {
         if let wl_registry::Event::Global {
-            name, interface, ..
+            name,
+            interface,
+            version,
         } = event
         {
             match interface.as_str() {
@@ -441,13 +668,31 @@ impl Dispatch<wl_registry::WlRegistry, QueueHandle<State>> for Globals {
                     state.seat = Some(seat);
                 }
                 "wl_compositor" => {
-                    let compositor: WlCompositor = registry.bind(name, 4, handle, ());
+                    // Version 6 tells the preferred buffer scale.
+                    let compositor: WlCompositor =
+                        registry.bind(name, version.clamp(4, 6), handle, ());
                     state.compositor = Some(compositor);
                 }
                 "wl_shm" => {
                     let shm: WlShm = registry.bind(name, 1, handle, ());
                     state.shm = Some(shm);
                 }
+                "zwp_virtual_keyboard_manager_v1" => {
+                    let manager = registry.bind(name, 1, handle, ());
+                    state.virtual_keyboard_manager = Some(manager);
+                }
+                "zwlr_layer_shell_v1" => {
+                    let layer_shell = registry.bind(name, 1, handle, ());
+                    state.layer_shell = Some(layer_shell);
+                }
+                "wp_viewporter" => {
+                    let viewporter = registry.bind(name, 1, handle, ());
+                    state.viewporter = Some(viewporter);
+                }
+                "wp_fractional_scale_manager_v1" => {
+                    let manager = registry.bind(name, 1, handle, ());
+                    state.fractional_scale_manager = Some(manager);
+                }
                 "zwp_tablet_manager_v2" => {
                     let tablet_manager = registry.bind(name, 1, handle, ());
                     state.tablet_manager = Some(tablet_manager);
@@ -462,6 +707,7 @@ impl Dispatch<ZwpTabletSeatV2, ()> for State {
     event_created_child!(Self, ZwpTabletSeatV2, [
        EVT_TABLET_ADDED_OPCODE => (ZwpTabletV2, ()),
        EVT_TOOL_ADDED_OPCODE => (ZwpTabletToolV2, ()),
+       EVT_PAD_ADDED_OPCODE => (ZwpTabletPadV2, ()),
     ]);
 
     fn event(
@@ -478,7 +724,9 @@ impl Dispatch<ZwpTabletSeatV2, ()> for State {
             zwp_tablet_seat_v2::Event::ToolAdded { id: _ } => {
                 info!("tablet tool added");
             }
-            zwp_tablet_seat_v2::Event::PadAdded { id: _ } => {}
+            zwp_tablet_seat_v2::Event::PadAdded { id: _ } => {
+                info!("tablet pad added");
+            }
             _ => {}
         }
     }
@@ -510,14 +758,31 @@ impl Dispatch<WlShmPool, ()> for State {
 }
 impl Dispatch<WlBuffer, ()> for State {
     fn event(
-        _state: &mut Self,
-        _proxy: &WlBuffer,
-        _event: <WlBuffer as Proxy>::Event,
+        state: &mut Self,
+        proxy: &WlBuffer,
+        event: <WlBuffer as Proxy>::Event,
         _data: &(),
         _conn: &Connection,
         _qhandle: &QueueHandle<Self>,
     ) {
-        trace!("buffer event")
+        if let wl_buffer::Event::Release = event {
+            trace!("buffer released");
+            state.on_buffer_release(proxy);
+        }
+    }
+}
+impl Dispatch<WlCallback, ()> for State {
+    fn event(
+        state: &mut Self,
+        _proxy: &WlCallback,
+        event: <WlCallback as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        if let wl_callback::Event::Done { .. } = event {
+            state.on_frame_done();
+        }
     }
 }
 impl Dispatch<ZwpInputMethodManagerV2, ()> for State {
@@ -535,14 +800,85 @@ impl Dispatch<ZwpInputMethodManagerV2, ()> for State {
 
 impl Dispatch<WlSeat, ()> for State {
     fn event(
-        _state: &mut Self,
-        _proxy: &WlSeat,
-        _event: <WlSeat as Proxy>::Event,
+        state: &mut Self,
+        proxy: &WlSeat,
+        event: <WlSeat as Proxy>::Event,
         _data: &(),
         _conn: &Connection,
-        _qhandle: &QueueHandle<Self>,
+        qhandle: &QueueHandle<Self>,
     ) {
         trace!("seat event");
+        if let wl_seat::Event::Capabilities {
+            capabilities: WEnum::Value(capabilities),
+        } = event
+        {
+            let has_touch = capabilities.contains(wl_seat::Capability::Touch);
+            if has_touch && state.touch.is_none() && state.config.touch.enabled {
+                info!("touchscreen added");
+                state.touch = Some(proxy.get_touch(qhandle, ()));
+            } else if !has_touch {
+                if let Some(touch) = state.touch.take() {
+                    info!("touchscreen removed");
+                    touch.release();
+                    state.on_touch_cancel();
+                }
+            }
+        }
+    }
+}
+
+impl Dispatch<WlTouch, ()> for State {
+    fn event(
+        state: &mut Self,
+        _proxy: &WlTouch,
+        event: <WlTouch as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            wl_touch::Event::Down {
+                serial: _,
+                time,
+                surface,
+                id,
+                x,
+                y,
+            } => {
+                if surface == state.surface {
+                    state.on_touch_down(Source::Touch(id), x, y, time);
+                }
+            }
+            wl_touch::Event::Motion { time, id, x, y } => {
+                state.on_motion(&Source::Touch(id), x, y, time);
+            }
+            wl_touch::Event::Up {
+                serial: _,
+                time: _,
+                id,
+            } => {
+                let source = Source::Touch(id);
+                state.on_up(&source);
+                state.inputs.remove(&source);
+            }
+            wl_touch::Event::Shape { id, major, minor } => {
+                trace!("touch shape: {id} {major} {minor}");
+                let source = Source::Touch(id);
+                if major > state.config.touch.palm_size
+                    && state.inputs.contact(&source) == Contact::Drawing
+                {
+                    info!("palm rejected");
+                    state.cancel_stroke(&source);
+                }
+            }
+            wl_touch::Event::Cancel => {
+                trace!("touch cancelled");
+                state.on_touch_cancel();
+            }
+            _ => {
+                trace!("other touch event")
+            }
+        }
     }
 }
 
@@ -585,16 +921,50 @@ impl Dispatch<ZwpInputMethodV2, ()> for State {
         match event {
             zwp_input_method_v2::Event::Activate => {
                 info!("activate");
+                state.pending = PendingState {
+                    active: Some(true),
+                    ..PendingState::default()
+                };
+            }
+            zwp_input_method_v2::Event::ContentType { hint, purpose } => {
+                let (WEnum::Value(hint), WEnum::Value(purpose)) = (hint, purpose) else {
+                    warn!("unknown content type {hint:?} {purpose:?}");
+                    return;
+                };
+                let mode = Mode::new(hint, purpose);
+                info!("content type {hint:?} {purpose:?}: {mode:?}");
+                state.pending.mode = Some(mode);
+            }
+            zwp_input_method_v2::Event::SurroundingText {
+                text,
+                cursor,
+                anchor,
+            } => {
+                let mode = state.pending.mode.unwrap_or(state.mode);
+                trace!(
+                    "surrounding text: {:?} {cursor} {anchor}",
+                    mode.loggable(&text)
+                );
+                state.pending.surrounding_text = Some(SurroundingText {
+                    text,
+                    cursor: cursor as usize,
+                    anchor: anchor as usize,
+                });
             }
             zwp_input_method_v2::Event::Deactivate => {
                 info!("deactivate");
+                state.pending = PendingState {
+                    active: Some(false),
+                    ..PendingState::default()
+                };
             }
             zwp_input_method_v2::Event::Done => {
                 state.input_method_serial += 1;
                 trace!("done");
+                state.apply_pending();
             }
             zwp_input_method_v2::Event::Unavailable => {
-                panic!("Input method unavailable.")
+                state.on_unavailable();
             }
             _ => {
                 trace!("other input method event")
@@ -603,9 +973,36 @@ impl Dispatch<ZwpInputMethodV2, ()> for State {
     }
 }
 
+impl Dispatch<ZwlrLayerSurfaceV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        layer_surface: &ZwlrLayerSurfaceV1,
+        event: <ZwlrLayerSurfaceV1 as Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_layer_surface_v1::Event::Configure {
+                serial,
+                width,
+                height,
+            } => {
+                trace!("panel configure: {serial} {width} {height}");
+                layer_surface.ack_configure(serial);
+                state.on_panel_configure(width, height);
+            }
+            zwlr_layer_surface_v1::Event::Closed => {
+                info!("panel closed");
+                state.hide();
+            }
+        }
+    }
+}
+
 impl Dispatch<ZwpInputPopupSurfaceV2, ()> for State {
     fn event(
-        _state: &mut Self,
+        state: &mut Self,
         _: &ZwpInputPopupSurfaceV2,
         event: <ZwpInputPopupSurfaceV2 as Proxy>::Event,
         _: &(),
@@ -620,21 +1017,49 @@ impl Dispatch<ZwpInputPopupSurfaceV2, ()> for State {
             height,
         } = event
         {
-            trace!("x: {}, y: {}, width: {}, height: {}", x, y, width, height)
+            trace!("x: {}, y: {}, width: {}, height: {}", x, y, width, height);
+            state.on_caret_moved(Caret {
+                x,
+                y,
+                width,
+                height,
+            });
         }
     }
 }
 
 impl Dispatch<WlSurface, ()> for State {
     fn event(
-        _state: &mut Self,
-        _proxy: &WlSurface,
-        _event: <WlSurface as Proxy>::Event,
+        state: &mut Self,
+        proxy: &WlSurface,
+        event: <WlSurface as Proxy>::Event,
         _data: &(),
         _conn: &Connection,
         _qhandle: &QueueHandle<Self>,
     ) {
         trace!("surface event");
+        // The fractional scale is more precise if there is one.
+        if let wl_surface::Event::PreferredBufferScale { factor } = event {
+            // Cursors get their scale from the writing surface.
+            if proxy == &state.surface && state.viewport.is_none() {
+                state.set_scale(factor as f64);
+            }
+        }
+    }
+}
+
+impl Dispatch<WpFractionalScaleV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _proxy: &WpFractionalScaleV1,
+        event: <WpFractionalScaleV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        if let wp_fractional_scale_v1::Event::PreferredScale { scale } = event {
+            state.set_scale(scale as f64 / 120.);
+        }
     }
 }
 
@@ -651,16 +1076,18 @@ impl Dispatch<WlPointer, ()> for State {
             wayland_client::protocol::wl_pointer::Event::Enter {
                 serial: _,
                 surface: _,
-                surface_x: _,
-                surface_y: _,
+                surface_x,
+                surface_y,
             } => {
-                trace!("enter")
+                trace!("enter");
+                state.inputs.get(&Source::Pointer).position =
+                    (surface_x, surface_y - state.ink_top as f64);
             }
             wayland_client::protocol::wl_pointer::Event::Leave { serial: _, surface } => {
                 trace!("leave");
-                if state.is_pen_down && surface == state.surface {
-                    state.is_pen_down = false;
-                    info!("pen up");
+                if surface == state.surface {
+                    state.on_up(&Source::Pointer);
+                    state.inputs.remove(&Source::Pointer);
                 }
             }
             wayland_client::protocol::wl_pointer::Event::Motion {
@@ -668,7 +1095,7 @@ impl Dispatch<WlPointer, ()> for State {
                 surface_x,
                 surface_y,
             } => {
-                state.on_motion(surface_x, surface_y, time);
+                state.on_motion(&Source::Pointer, surface_x, surface_y, time);
             }
             wayland_client::protocol::wl_pointer::Event::Button {
                 serial,
@@ -677,12 +1104,14 @@ impl Dispatch<WlPointer, ()> for State {
                 state: button_state,
             } => {
                 trace!("button: {serial} {time} {button} {button_state:?}");
+                let mouse = &state.config.mouse;
+                if !mouse.enabled || button != mouse.button {
+                    return;
+                }
                 if let WEnum::Value(ButtonState::Pressed) = button_state {
-                    state.pressure = None;
-                    state.on_down();
+                    state.on_down(&Source::Pointer);
                 } else {
-                    state.pressure = None;
-                    state.on_up();
+                    state.on_up(&Source::Pointer);
                 }
             }
             _ => {
@@ -708,25 +1137,74 @@ impl Dispatch<ZwpTabletManagerV2, ()> for State {
 impl Dispatch<ZwpTabletToolV2, ()> for State {
     fn event(
         state: &mut Self,
-        _proxy: &ZwpTabletToolV2,
+        proxy: &ZwpTabletToolV2,
         event: <ZwpTabletToolV2 as Proxy>::Event,
         _data: &(),
         _conn: &Connection,
         _qhandle: &QueueHandle<Self>,
     ) {
+        let is_eraser = state.tools.get(&proxy.id()) == Some(&zwp_tablet_tool_v2::Type::Eraser);
+        let source = Source::Tool(proxy.id());
         match event {
+            zwp_tablet_tool_v2::Event::Type { tool_type } => {
+                info!("tablet tool type: {tool_type:?}");
+                if let WEnum::Value(tool_type) = tool_type {
+                    state.tools.insert(proxy.id(), tool_type);
+                }
+            }
+            zwp_tablet_tool_v2::Event::ProximityIn {
+                serial,
+                tablet: _,
+                surface,
+            } => {
+                if surface != state.surface {
+                    return;
+                }
+                trace!("proximity in: {source:?}");
+                state.inputs.get(&source).tool = Some((proxy.clone(), serial));
+                state.set_tool_cursor(proxy, serial, is_eraser);
+                // The hand holding the pen was resting on the screen.
+                if let Some(touch @ Source::Touch(_)) = state.inputs.active().cloned() {
+                    info!("palm rejected");
+                    state.cancel_stroke(&touch);
+                }
+            }
+            zwp_tablet_tool_v2::Event::ProximityOut => {
+                state.inputs.get(&source).frame.proximity_out = true;
+            }
+            zwp_tablet_tool_v2::Event::Removed => {
+                info!("tablet tool removed");
+                state.tools.remove(&proxy.id());
+                state.inputs.remove(&source);
+                proxy.destroy();
+            }
             zwp_tablet_tool_v2::Event::Down { serial: _ } => {
-                state.on_down();
+                state.inputs.get(&source).frame.down = true;
             }
             zwp_tablet_tool_v2::Event::Up => {
-                state.on_up();
+                state.inputs.get(&source).frame.up = true;
             }
             zwp_tablet_tool_v2::Event::Motion { x, y } => {
-                state.on_motion(x, y, 0); // TODO: no time available
+                state.inputs.get(&source).frame.motion = Some((x, y));
             }
             zwp_tablet_tool_v2::Event::Pressure { pressure } => {
                 trace!("pressure: {}", pressure);
-                state.pressure = Some(pressure);
+                state.inputs.get(&source).pressure = Some(pressure);
+            }
+            zwp_tablet_tool_v2::Event::Tilt { tilt_x, tilt_y } => {
+                trace!("tilt: {tilt_x} {tilt_y}");
+                state.inputs.get(&source).tilt = Some((tilt_x, tilt_y));
+            }
+            zwp_tablet_tool_v2::Event::Rotation { degrees } => {
+                trace!("rotation: {degrees}");
+                state.inputs.get(&source).rotation = Some(degrees);
+            }
+            zwp_tablet_tool_v2::Event::Distance { distance } => {
+                trace!("distance: {distance}");
+                state.inputs.get(&source).distance = Some(distance);
+            }
+            zwp_tablet_tool_v2::Event::Frame { time } => {
+                state.on_tool_frame(&source, is_eraser, time);
             }
             zwp_tablet_tool_v2::Event::Button {
                 serial,
@@ -735,16 +1213,11 @@ impl Dispatch<ZwpTabletToolV2, ()> for State {
             } => {
                 info!("button: {serial} {button} {button_state:?}");
                 if let WEnum::Value(zwp_tablet_tool_v2::ButtonState::Pressed) = button_state {
-                    match button {
-                        331 => {
-                            state.enter_input();
-                        }
-                        332 => {
-                            state.undo();
-                        }
-                        _ => {
-                            warn!("unhandled pen button: {}", button)
+                    match state.config.bindings.stylus(button) {
+                        Some(action) => {
+                            state.perform(action);
                         }
+                        None => warn!("unhandled pen button: {}", button),
                     }
                 }
             }
@@ -755,7 +1228,66 @@ impl Dispatch<ZwpTabletToolV2, ()> for State {
     }
 }
 
-delegate_noop!(State: ignore ZwpTabletPadV2);
+impl Dispatch<ZwpTabletPadV2, ()> for State {
+    event_created_child!(Self, ZwpTabletPadV2, [
+       EVT_GROUP_OPCODE => (ZwpTabletPadGroupV2, ()),
+    ]);
+
+    fn event(
+        state: &mut Self,
+        _proxy: &ZwpTabletPadV2,
+        event: <ZwpTabletPadV2 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        if let zwp_tablet_pad_v2::Event::Button {
+            time,
+            button,
+            state: button_state,
+        } = event
+        {
+            info!("pad button: {time} {button} {button_state:?}");
+            if let WEnum::Value(zwp_tablet_pad_v2::ButtonState::Pressed) = button_state {
+                match state.config.bindings.pad(button) {
+                    Some(action) => {
+                        state.perform(action);
+                    }
+                    None => warn!("unhandled pad button: {}", button),
+                }
+            }
+        } else {
+            trace!("other pad event")
+        }
+    }
+}
+
+impl Dispatch<ZwpTabletPadGroupV2, ()> for State {
+    event_created_child!(Self, ZwpTabletPadGroupV2, [
+       EVT_RING_OPCODE => (ZwpTabletPadRingV2, ()),
+       EVT_STRIP_OPCODE => (ZwpTabletPadStripV2, ()),
+    ]);
+
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZwpTabletPadGroupV2,
+        _event: <ZwpTabletPadGroupV2 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        trace!("pad group event");
+    }
+}
+
+delegate_noop!(State: ZwlrLayerShellV1);
+delegate_noop!(State: WpViewporter);
+delegate_noop!(State: WpViewport);
+delegate_noop!(State: WpFractionalScaleManagerV1);
+delegate_noop!(State: ZwpVirtualKeyboardManagerV1);
+delegate_noop!(State: ZwpVirtualKeyboardV1);
+delegate_noop!(State: ignore ZwpTabletPadRingV2);
+delegate_noop!(State: ignore ZwpTabletPadStripV2);
 delegate_noop!(State: ignore ZwpTabletV2);
 
 impl Dispatch<ZwpInputMethodKeyboardGrabV2, ()> for State {
@@ -771,54 +1303,56 @@ impl Dispatch<ZwpInputMethodKeyboardGrabV2, ()> for State {
             zwp_input_method_keyboard_grab_v2::Event::Keymap { format, fd, size } => unsafe {
                 if let WEnum::Value(KeymapFormat::XkbV1) = format {
                     info!("XKB V1 Keymap");
-                    let context = xkbcommon::xkb::Context::new(CONTEXT_NO_FLAGS);
-                    let keymap = Keymap::new_from_fd(
-                        &context,
-                        fd,
-                        size as usize,
-                        KEYMAP_FORMAT_TEXT_V1,
-                        KEYMAP_COMPILE_NO_FLAGS,
-                    )
-                    .unwrap()
-                    .unwrap();
-                    let xkb_state = xkbcommon::xkb::State::new(&keymap);
-                    state.xkb_state = Some(XkbState {
-                        keymap,
-                        xkb_context: context,
-                        state: xkb_state,
-                    });
+                    if let Some(virtual_keyboard) = &state.virtual_keyboard {
+                        virtual_keyboard.keymap(KeymapFormat::XkbV1.into(), fd.as_fd(), size);
+                    }
+                    state.xkb_state = XkbState::from_fd(fd, size);
+                    if state.xkb_state.is_none() {
+                        warn!("failed to compile keymap");
+                    }
                 } else {
                     panic!("Unsupported keymap format")
                 }
             },
+            zwp_input_method_keyboard_grab_v2::Event::Modifiers {
+                serial: _,
+                mods_depressed,
+                mods_latched,
+                mods_locked,
+                group,
+            } => {
+                trace!("modifiers: {mods_depressed} {mods_latched} {mods_locked} {group}");
+                if let Some(xkb_state) = &mut state.xkb_state {
+                    xkb_state.update_modifiers(mods_depressed, mods_latched, mods_locked, group);
+                }
+                if let Some(virtual_keyboard) = &state.virtual_keyboard {
+                    virtual_keyboard.modifiers(mods_depressed, mods_latched, mods_locked, group);
+                }
+            }
+            zwp_input_method_keyboard_grab_v2::Event::RepeatInfo { rate, delay } => {
+                info!("key repeat: {rate}/s after {delay}ms");
+            }
             zwp_input_method_keyboard_grab_v2::Event::Key {
                 serial,
                 time,
                 key,
                 state: key_state,
             } => {
-                trace!("key: {serial} {time} {key} {key_state:?}");
-                if let WEnum::Value(KeyState::Pressed) = key_state {
-                    let c = state
-                        .xkb_state
-                        .as_ref()
-                        .unwrap()
-                        .state
-                        .key_get_one_sym((key + 8).into());
-                    info!("key: {c:?}");
-                    if let Some(c) = c.key_char() {
-                        match c {
-                            'z' => {
-                                state.undo();
-                            }
-                            '\r' => {
-                                state.enter_input();
-                            }
-                            _ => {
-                                info!("unhandled key: {c:?}")
-                            }
-                        }
+                // Keys typed into sensitive fields stay out of the log.
+                if !state.mode.sensitive {
+                    trace!("key: {serial} {time} {key} {key_state:?}");
+                }
+                let consumed = if let WEnum::Value(KeyState::Pressed) = key_state {
+                    let consumed = state.on_key(key);
+                    if consumed {
+                        state.consumed_keys.insert(key);
                     }
+                    consumed
+                } else {
+                    state.consumed_keys.remove(&key)
+                };
+                if !consumed {
+                    state.forward_key(time, key, key_state.into());
                 }
             }
             _ => {
@@ -831,97 +1365,699 @@ impl Dispatch<ZwpInputMethodKeyboardGrabV2, ()> for State {
 impl State {
     fn redraw(&mut self) {
         trace!("redraw");
-        fill_background(&self.cairo_ctx);
-        for stroke in &self.strokes {
+        fill_background(&self.cairo_ctx, self.config.ui.background_color);
+        self.clip_to_ink();
+        self.draw_strokes(&self.cairo_ctx);
+        self.cairo_ctx.restore().unwrap();
+        self.draw_candidates();
+
+        self.display();
+    }
+
+    fn draw_strokes(&self, ctx: &cairo::Context) {
+        for (i, stroke) in self.strokes.iter().enumerate() {
+            if self.erased.contains(&i) {
+                continue;
+            }
             let mut points = stroke.points.iter();
             if let Some(first) = points.next() {
-                self.cairo_ctx.move_to(first.x, first.y);
+                ctx.move_to(first.x, first.y);
                 for point in points {
-                    self.set_pressure(point.pressure);
+                    ctx.set_line_width(self.line_width(point));
                     trace!(
                         "draw point ({}, {}, {:?})",
                         point.x,
                         point.y,
                         point.pressure
                     );
-                    self.cairo_ctx.line_to(point.x, point.y);
-                    self.cairo_ctx.stroke().unwrap();
-                    self.cairo_ctx.move_to(point.x, point.y);
+                    ctx.line_to(point.x, point.y);
+                    ctx.stroke().unwrap();
+                    ctx.move_to(point.x, point.y);
                 }
             }
         }
+    }
 
-        self.display();
+    /// Restricts drawing to the writing area until the next `restore`.
+    fn clip_to_ink(&self) {
+        self.cairo_ctx.save().unwrap();
+        self.cairo_ctx
+            .rectangle(0., 0., self.width as f64, self.height as f64);
+        self.cairo_ctx.clip();
+    }
+
+    /// Whether `y` in the coordinates of the writing area is on the
+    /// candidates.
+    fn in_candidate_strip(&self, y: f64) -> bool {
+        y < 0. || y >= self.height as f64
+    }
+
+    fn draw_candidates(&mut self) {
+        let y = if self.ink_top > 0 {
+            -self.config.ui.candidate_height
+        } else {
+            self.height
+        };
+        self.candidates.draw(
+            &self.cairo_ctx,
+            y as f64,
+            self.width as f64,
+            self.config.ui.candidate_height as f64,
+            &self.config.ui,
+        );
     }
 
     fn display(&mut self) {
-        self.surface.attach(Some(&self.buffer), 0, 0);
+        let mapped = match &self.panel {
+            Some(panel) => panel.configured,
+            None => self.config.ui.mode == UiMode::Popup,
+        };
+        if !self.enabled || !self.active || !mapped {
+            return;
+        }
+        self.needs_display = true;
+        if !self.frame_pending {
+            self.present();
+        }
+    }
+
+    /// Shows the canvas in a buffer the compositor is not using. Without one,
+    /// waits for a release.
+    fn present(&mut self) {
+        self.cairo_surface.flush();
+        let data = unsafe {
+            std::slice::from_raw_parts(self.canvas.as_ptr() as *const u8, self.canvas.len() * 4)
+        };
+        let buffer = match self.buffers.next(data, &self.wayland_qh) {
+            Ok(Some(buffer)) => buffer,
+            Ok(None) => {
+                trace!("no free buffer");
+                return;
+            }
+            Err(e) => {
+                // The frame is skipped, the next one tries again.
+                error!("failed to grow the shm pool: {e}");
+                return;
+            }
+        };
+        match &self.viewport {
+            Some((viewport, _)) => {
+                viewport.set_destination(self.width, self.height + self.config.ui.candidate_height)
+            }
+            None => self.surface.set_buffer_scale(self.scale as i32),
+        }
+        self.surface.attach(Some(buffer), 0, 0);
         self.surface.damage(0, 0, i32::MAX, i32::MAX);
+        self.surface.frame(&self.wayland_qh, ());
         self.surface.commit();
+        self.needs_display = false;
+        self.frame_pending = true;
+    }
+
+    fn on_frame_done(&mut self) {
+        self.frame_pending = false;
+        if self.needs_display {
+            self.display();
+        }
+    }
+
+    fn on_buffer_release(&mut self, buffer: &WlBuffer) {
+        self.buffers.release(buffer);
+        if self.needs_display && !self.frame_pending {
+            self.display();
+        }
     }
 
-    fn draw_new_point(&mut self, x: f64, y: f64, pressure: Option<u32>) {
-        trace!("draw new point ({}, {})", x, y);
-        let stroke = self.strokes.last().unwrap();
-        if let Some(point) = stroke.points.last() {
-            self.set_pressure(pressure);
+    fn draw_new_point(&mut self, new: &InkPoint) {
+        trace!("draw new point ({}, {})", new.x, new.y);
+        if let Some(point) = self.strokes.last().and_then(|stroke| stroke.points.last()) {
+            self.clip_to_ink();
+            self.cairo_ctx.set_line_width(self.line_width(new));
             self.cairo_ctx.move_to(point.x, point.y);
-            self.cairo_ctx.line_to(x, y);
+            self.cairo_ctx.line_to(new.x, new.y);
             self.cairo_ctx.stroke().unwrap();
-        } else {
-            self.cairo_ctx.move_to(x, y);
+            self.cairo_ctx.restore().unwrap();
         }
         self.display()
     }
 
-    fn set_pressure(&self, pressure: Option<u32>) {
-        let line_width = if let Some(pressure) = pressure {
-            (pressure as f64 / 65535.) * self.line_width
+    fn line_width(&self, point: &InkPoint) -> f64 {
+        let mut line_width = self.config.ui.line_width;
+        if let Some(pressure) = point.pressure {
+            line_width *= pressure as f64 / 65535.;
+        }
+        // A tilted pen draws with the side of its tip, like a pencil.
+        if let Some((x, y)) = point.tilt {
+            line_width *= 1. + TILT_WIDTH * x.hypot(y).min(90.) / 90.;
+        }
+        line_width
+    }
+
+    fn on_motion(&mut self, source: &Source, surface_x: f64, surface_y: f64, time: u32) {
+        trace!("motion: {source:?} {time} {surface_x}, {surface_y}");
+        // From here on in the coordinates of the writing area.
+        let surface_y = surface_y - self.ink_top as f64;
+        let input = self.inputs.get(source);
+        input.position = (surface_x, surface_y);
+        match input.contact {
+            Contact::Erasing => self.erase_to(surface_x, surface_y),
+            Contact::Drawing => self.add_point(source, time),
+            Contact::Hovering | Contact::Ignored => {}
+        }
+    }
+
+    /// Adds the position of `source` to the stroke it is drawing.
+    fn add_point(&mut self, source: &Source, time: u32) {
+        let input = self.inputs.get(source);
+        if input.contact != Contact::Drawing {
+            return;
+        }
+        let (x, y) = input.position;
+        let point = InkPoint {
+            x,
+            y,
+            time,
+            pressure: input.pressure,
+            tilt: input.tilt,
+            rotation: input.rotation,
+            distance: input.distance,
+        };
+        self.draw_new_point(&point);
+        let Some(stroke) = self.strokes.last_mut() else {
+            return;
+        };
+        stroke.points.push(point);
+        self.max_x = self.max_x.max(x);
+        self.max_y = self.max_y.max(y);
+        trace!("add point ({x}, {y}) at {time}");
+    }
+
+    /// Shows where the tip of `tool` is while it hovers over the surface.
+    fn set_tool_cursor(&mut self, tool: &ZwpTabletToolV2, serial: u32, is_eraser: bool) {
+        if !self.config.ui.hover_cursor {
+            return;
+        }
+        if self.cursors.is_none() {
+            let scale = self.scale.ceil() as i32;
+            match Cursors::new(
+                &self.compositor,
+                &self.shm,
+                &self.wayland_qh,
+                &self.config.ui,
+                scale,
+            ) {
+                Ok(cursors) => self.cursors = Some(cursors),
+                Err(e) => {
+                    warn!("failed to create the pen cursor: {e}");
+                    return;
+                }
+            }
+        }
+        let cursors = self.cursors.as_ref().unwrap();
+        let cursor = if is_eraser {
+            &cursors.eraser
         } else {
-            self.line_width
+            &cursors.pen
         };
-        self.cairo_ctx.set_line_width(line_width);
+        tool.set_cursor(
+            serial,
+            Some(&cursor.surface),
+            cursor.hotspot,
+            cursor.hotspot,
+        );
     }
 
-    fn on_motion(&mut self, surface_x: f64, surface_y: f64, time: u32) {
-        trace!("motion: {time} {surface_x}, {surface_y}");
-        if self.is_pen_down {
-            self.draw_new_point(surface_x, surface_y, self.pressure);
-            self.strokes.last_mut().unwrap().points.push(InkPoint {
-                x: surface_x,
-                y: surface_y,
-                time,
-                pressure: self.pressure,
-            });
-            self.max_x = self.max_x.max(surface_x);
-            self.max_y = self.max_y.max(surface_y);
-            trace!("add point ({surface_x}, {surface_y}) at {time}");
+    /// Handles the events of a tablet tool since the last frame.
+    fn on_tool_frame(&mut self, source: &Source, is_eraser: bool, time: u32) {
+        let frame = std::mem::take(&mut self.inputs.get(source).frame);
+        if let Some((x, y)) = frame.motion {
+            self.on_motion(source, x, y, time);
+        }
+        if frame.down {
+            if is_eraser {
+                self.on_erase_down(source);
+            } else {
+                self.on_down(source);
+                self.add_point(source, time);
+            }
+        }
+        if frame.up {
+            if is_eraser {
+                self.on_erase_up(source);
+            } else {
+                self.on_up(source);
+            }
+        }
+        if frame.proximity_out {
+            trace!("proximity out: {source:?}");
+            // Without an up event, when the pen leaves the surface mid-stroke.
+            self.on_up(source);
+            self.on_erase_up(source);
+            self.inputs.remove(source);
+        }
+    }
+
+    fn on_down(&mut self, source: &Source) {
+        if !self.enabled {
+            return;
+        }
+        let busy = self.inputs.active().is_some();
+        let (x, y) = self.inputs.get(source).position;
+        let on_candidates = self.in_candidate_strip(y);
+        let input = self.inputs.get(source);
+        if busy || on_candidates {
+            input.contact = Contact::Ignored;
+        }
+        if busy {
+            trace!("ignore {source:?} while another source writes");
+            return;
         }
+        if on_candidates {
+            if let Some(index) = self.candidates.hit(x) {
+                self.select_candidate(index);
+            }
+            return;
+        }
+        input.contact = Contact::Drawing;
+        self.pen_down_at = Instant::now();
+        self.strokes.push(Stroke {
+            points: vec![],
+            device: source.device(),
+        });
+        self.update_keyboard_grab();
+        info!("pen down, #{} ({:?})", self.strokes.len(), source.device());
     }
 
-    fn on_down(&mut self) {
-        self.is_pen_down = true;
-        self.strokes.push(Stroke { points: vec![] });
-        info!("pen down, #{}", self.strokes.len());
+    /// Starts a stroke for a finger, unless there are other fingers or a pen
+    /// near the screen, in which case it is likely a palm.
+    fn on_touch_down(&mut self, source: Source, x: f64, y: f64, time: u32) {
+        self.inputs.get(&source).position = (x, y - self.ink_top as f64);
+        if self.inputs.has(Device::Touch, &source) || self.inputs.has(Device::Pen, &source) {
+            trace!("ignore {source:?}");
+            self.inputs.get(&source).contact = Contact::Ignored;
+            return;
+        }
+        self.on_down(&source);
+        self.add_point(&source, time);
     }
 
-    fn on_up(&mut self) {
-        self.is_pen_down = false;
+    /// Throws away the stroke being drawn by `source`.
+    fn cancel_stroke(&mut self, source: &Source) {
+        let input = self.inputs.get(source);
+        if input.contact != Contact::Drawing {
+            return;
+        }
+        input.contact = Contact::Ignored;
+        self.strokes.pop();
+        self.update_keyboard_grab();
+        self.redraw();
+    }
+
+    fn on_touch_cancel(&mut self) {
+        for source in self.inputs.touches() {
+            self.cancel_stroke(&source);
+            self.inputs.remove(&source);
+        }
+    }
+
+    /// Handles a key press, returning false if it should go to the
+    /// application instead.
+    fn on_key(&mut self, key: u32) -> bool {
+        let Some(xkb_state) = &self.xkb_state else {
+            return false;
+        };
+        let modifiers = xkb_state.modifiers();
+        let sym = xkb_state.base_sym(key);
+        if !self.mode.sensitive {
+            trace!("key: {:?} {modifiers:?} ({:?})", sym, xkb_state.sym(key));
+        }
+        match self.config.bindings.key(modifiers, sym) {
+            Some(action) => self.perform(action),
+            None => false,
+        }
+    }
+
+    /// Returns false if the action does not apply right now, e.g. because
+    /// there is no such candidate.
+    fn perform(&mut self, action: Action) -> bool {
+        if !self.enabled && action != Action::ToggleMode {
+            trace!("handwriting is off, ignoring {action:?}");
+            return false;
+        }
+        info!("action: {action:?}");
+        match action {
+            Action::Commit => self.enter_input(),
+            Action::Undo => return self.undo(),
+            Action::Redo => return self.redo(),
+            Action::Clear => self.clear(),
+            Action::NextCandidate | Action::PreviousCandidate => {
+                if self.candidates.is_empty() {
+                    return false;
+                }
+                let len = self.candidates.len();
+                let step = if action == Action::NextCandidate {
+                    1
+                } else {
+                    len - 1
+                };
+                return self.select_candidate((self.candidates.selected() + step) % len);
+            }
+            Action::SelectCandidate(index) => return self.select_candidate(index),
+            Action::ToggleMode => self.toggle_mode(),
+        }
+        true
+    }
+
+    fn toggle_mode(&mut self) {
+        self.enabled = !self.enabled;
+        info!("handwriting {}", if self.enabled { "on" } else { "off" });
+        if self.enabled {
+            self.update_keyboard_grab();
+            if self.active {
+                self.show();
+            }
+        } else {
+            self.clear();
+            self.hide();
+        }
+    }
+
+    fn show(&mut self) {
+        // A hidden surface gets no frame callbacks.
+        self.frame_pending = false;
+        if self.config.ui.mode == UiMode::Popup {
+            self.display();
+            return;
+        }
+        if let Some(panel) = &self.panel {
+            if panel.configured {
+                self.restore_size();
+            }
+            return;
+        }
+        // The old surface may already have had a buffer, which a new layer
+        // surface must not have.
+        self.surface.destroy();
+        self.surface = self.compositor.create_surface(&self.wayland_qh, ());
+        self.scale_surface();
+        let layer_shell = self
+            .layer_shell
+            .as_ref()
+            .expect("init falls back to popup mode without layer shell");
+        let layer_surface = layer_shell.get_layer_surface(
+            &self.surface,
+            None,
+            zwlr_layer_shell_v1::Layer::Top,
+            NAME.to_string(),
+            &self.wayland_qh,
+            (),
+        );
+        let ui = &self.config.ui;
+        if ui.mode == UiMode::Floating {
+            layer_surface.set_size(ui.panel_width as u32, ui.panel_height as u32);
+            layer_surface.set_anchor(zwlr_layer_surface_v1::Anchor::Bottom);
+            layer_surface.set_margin(0, 0, PANEL_MARGIN, 0);
+        } else {
+            layer_surface.set_size(0, ui.panel_height as u32);
+            layer_surface.set_anchor(
+                zwlr_layer_surface_v1::Anchor::Bottom
+                    | zwlr_layer_surface_v1::Anchor::Left
+                    | zwlr_layer_surface_v1::Anchor::Right,
+            );
+            if ui.panel_exclusive {
+                layer_surface.set_exclusive_zone(ui.panel_height);
+            }
+        }
+        self.surface.commit();
+        self.panel = Some(Panel {
+            layer_surface,
+            configured: false,
+        });
+        info!("show panel");
+    }
+
+    fn hide(&mut self) {
+        if self.config.ui.mode == UiMode::Popup {
+            self.surface.attach(None, 0, 0);
+            self.surface.commit();
+        } else if let Some(panel) = self.panel.take() {
+            panel.layer_surface.destroy();
+            info!("hide panel");
+        }
+    }
+
+    fn on_panel_configure(&mut self, width: u32, height: u32) {
+        let Some(panel) = &mut self.panel else {
+            return;
+        };
+        panel.configured = true;
+        if width > 0 {
+            self.original_width = width as i32;
+        }
+        if height > 0 {
+            self.original_height = (height as i32 - self.config.ui.candidate_height).max(1);
+        }
+        info!(
+            "panel configured: {}x{}",
+            self.original_width, self.original_height
+        );
+        self.restore_size();
+    }
+
+    /// Grabs the keyboard while there is ink and hands it back to the
+    /// focused application afterwards. While handwriting is off, it is also
+    /// grabbed for the key that turns it back on, if other keys can be
+    /// forwarded.
+    fn update_keyboard_grab(&mut self) {
+        let wanted = !self.strokes.is_empty() || (!self.enabled && self.virtual_keyboard.is_some());
+        if wanted && self.keyboard_grab.is_none() {
+            trace!("grab keyboard");
+            self.keyboard_grab = Some(self.input_method.grab_keyboard(&self.wayland_qh, ()));
+        } else if !wanted {
+            if let Some(grab) = self.keyboard_grab.take() {
+                trace!("release keyboard");
+                grab.release();
+                self.consumed_keys.clear();
+            }
+        }
+    }
+
+    fn forward_key(&self, time: u32, key: u32, key_state: u32) {
+        match &self.virtual_keyboard {
+            Some(virtual_keyboard) => {
+                if !self.mode.sensitive {
+                    trace!("forward key {key} {key_state}");
+                }
+                virtual_keyboard.key(time, key, key_state);
+            }
+            None if self.mode.sensitive => info!("unhandled key"),
+            None => info!("unhandled key: {key}"),
+        }
+    }
+
+    fn on_erase_down(&mut self, source: &Source) {
+        let busy = self.inputs.active().is_some();
+        let (x, y) = self.inputs.get(source).position;
+        let ignored = !self.enabled || busy || self.in_candidate_strip(y);
+        let input = self.inputs.get(source);
+        if ignored {
+            input.contact = Contact::Ignored;
+            return;
+        }
+        input.contact = Contact::Erasing;
+        self.eraser_position = (x, y);
+        info!("eraser down");
+        self.erase_to(x, y);
+    }
+
+    /// Hides the strokes crossed by the eraser since its last position.
+    fn erase_to(&mut self, x: f64, y: f64) {
+        let from = self.eraser_position;
+        self.eraser_position = (x, y);
+        let radius = (self.config.ui.eraser_width + self.config.ui.line_width) / 2.;
+        let mut hit = false;
+        for (i, stroke) in self.strokes.iter().enumerate() {
+            if !self.erased.contains(&i) && stroke.crosses(from, (x, y), radius) {
+                trace!("erase stroke #{}", i + 1);
+                self.erased.insert(i);
+                hit = true;
+            }
+        }
+        if hit {
+            self.redraw();
+        }
+    }
+
+    fn on_erase_up(&mut self, source: &Source) {
+        if std::mem::take(&mut self.inputs.get(source).contact) != Contact::Erasing {
+            return;
+        }
+        info!("eraser up, {} strokes erased", self.erased.len());
+        if self.erased.is_empty() {
+            return;
+        }
+        let mut erased: Vec<usize> = self.erased.drain().collect();
+        erased.sort_unstable();
+        let edit = Edit::remove_strokes(&mut self.strokes, &erased);
+        self.history.push(edit);
+        self.on_strokes_changed();
+    }
+
+    fn on_up(&mut self, source: &Source) {
+        if std::mem::take(&mut self.inputs.get(source).contact) != Contact::Drawing {
+            return;
+        }
+        info!("pen up");
+
+        if self.apply_gesture() {
+            return;
+        }
+        self.history.push(Edit::add_stroke(self.strokes.len() - 1));
 
         self.auto_resize();
 
         self.recognize();
-        info!("pen up");
+    }
+
+    /// Carries out the stroke just finished instead of keeping it as ink if
+    /// it is a gesture. Returns false if it is not.
+    fn apply_gesture(&mut self) -> bool {
+        let Some(stroke) = self.strokes.last() else {
+            return false;
+        };
+        let limits = gesture::Limits {
+            line_width: self.config.ui.line_width,
+            height: self.height as f64,
+            hold_time: Duration::from_millis(self.config.gestures.hold_time),
+        };
+        let Some(gesture) = gesture::recognize(stroke, self.pen_down_at.elapsed(), &limits) else {
+            return false;
+        };
+        let gestures = &self.config.gestures;
+        let enabled = match gesture {
+            Gesture::ScratchOut => gestures.scratch_out,
+            Gesture::FlickRight | Gesture::FlickLeft => gestures.flicks,
+            Gesture::Hook => gestures.newline,
+            // Holding without any ink is more likely a dot.
+            Gesture::Hold => gestures.hold_to_commit && self.strokes.len() > 1,
+        };
+        if !enabled {
+            return false;
+        }
+        info!("gesture: {gesture:?}");
+        match gesture {
+            Gesture::ScratchOut => {
+                let covered = self.strokes_under(stroke);
+                if covered.is_empty() {
+                    return false;
+                }
+                info!("scratch-out over {} strokes", covered.len());
+                self.strokes.pop();
+                let edit = Edit::remove_strokes(&mut self.strokes, &covered);
+                self.history.push(edit);
+                self.on_strokes_changed();
+            }
+            Gesture::FlickRight => {
+                self.strokes.pop();
+                self.enter_input_with(" ");
+            }
+            Gesture::Hook => {
+                self.strokes.pop();
+                self.enter_input_with("\n");
+            }
+            Gesture::Hold => {
+                self.strokes.pop();
+                self.enter_input();
+            }
+            Gesture::FlickLeft => {
+                self.strokes.pop();
+                if self.strokes.is_empty() {
+                    self.redraw();
+                    self.update_keyboard_grab();
+                    self.delete_before_cursor();
+                } else {
+                    // Backspace on the ink rather than on the text.
+                    let last = self.strokes.len() - 1;
+                    let edit = Edit::remove_strokes(&mut self.strokes, &[last]);
+                    self.history.push(edit);
+                    self.on_strokes_changed();
+                }
+            }
+        }
+        true
+    }
+
+    /// The other strokes that mostly lie within the bounding box of `stroke`.
+    fn strokes_under(&self, stroke: &Stroke) -> Vec<usize> {
+        let points: Vec<_> = stroke.points.iter().map(|p| (p.x, p.y)).collect();
+        let Some((min, max)) = geometry::bounds(&points) else {
+            return vec![];
+        };
+        let margin = self.config.ui.line_width;
+        let inside = |p: &InkPoint| {
+            (min.0 - margin..=max.0 + margin).contains(&p.x)
+                && (min.1 - margin..=max.1 + margin).contains(&p.y)
+        };
+        self.strokes
+            .iter()
+            .enumerate()
+            .filter(|&(_, s)| !std::ptr::eq(s, stroke) && !s.points.is_empty())
+            .filter(|(_, s)| 2 * s.points.iter().filter(|p| inside(p)).count() >= s.points.len())
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Makes sure the result of the last request is not used.
+    fn cancel_recognition(&mut self) {
+        if !self.recognition_pending {
+            return;
+        }
+        self.recognition_pending = false;
+        if let Err(e) = self.recognition.cancel(self.recognition_id) {
+            warn!("failed to cancel request {}: {e}", self.recognition_id);
+        }
     }
 
     fn recognize(&mut self) {
-        self.recognition
-            .stdin
-            .as_ref()
-            .unwrap()
-            .write_all(format!("{} {}\n", self.width, self.height).as_bytes())
-            .unwrap();
-        self.recognition.stdin.as_ref().unwrap().flush().unwrap();
+        self.cancel_recognition();
+        if !self.enabled {
+            return;
+        }
+        // Engines get black ink on white, whatever the colors on screen.
+        let width = self.to_buffer(self.width);
+        let height = self.to_buffer(self.height);
+        let (canvas, surface) = create_canvas(width, height);
+        let ctx = cairo::Context::new(&surface).unwrap();
+        ctx.scale(self.scale, self.scale);
+        fill_background(&ctx, Color::rgba(1., 1., 1., 1.));
+        set_line(&ctx, Color::rgba(0., 0., 0., 1.), self.config.ui.line_width);
+        self.draw_strokes(&ctx);
+        drop(ctx);
+        surface.flush();
+        let data =
+            unsafe { std::slice::from_raw_parts(canvas.as_ptr() as *const u8, canvas.len() * 4) };
+        let ink = Ink {
+            width: self.width,
+            height: self.height,
+            image: Image {
+                width,
+                height,
+                stride: width * 4,
+                data,
+            },
+            strokes: &self.strokes,
+            context: match &self.surrounding_text {
+                Some(surrounding) if !self.mode.sensitive => surrounding.context(),
+                _ => "",
+            },
+            alphabet: self.mode.alphabet,
+            sensitive: self.mode.sensitive,
+        };
+        self.recognition_id += 1;
+        self.recognition_pending = true;
+        if let Err(e) = self.recognition.submit(self.recognition_id, &ink) {
+            error!("failed to submit ink for recognition: {e}");
+        }
     }
 
     fn resize(&mut self, width: i32, height: i32) {
@@ -929,65 +2065,380 @@ impl State {
         self.max_y = 0.;
         self.width = width;
         self.height = height;
-        let stride = width * 4;
-        let buffer_size = stride * height;
-        let data: &mut [u8] = unsafe {
-            std::slice::from_raw_parts_mut(self.data_ptr as *mut u8, buffer_size as usize)
-        };
-        self.buffer.destroy();
-        self.buffer = self.shm_pool.create_buffer(
-            0,
-            width,
-            height,
-            stride,
-            wayland_client::protocol::wl_shm::Format::Argb8888,
-            &self.wayland_qh,
-            (),
-        );
-        self.cairo_surface = cairo::ImageSurface::create_for_data(
-            data,
-            cairo::Format::ARgb32,
-            width,
-            height,
-            stride,
-        )
-        .unwrap();
+        let buffer_width = self.to_buffer(width);
+        let buffer_height = self.to_buffer(height + self.config.ui.candidate_height);
+        self.buffers.resize(buffer_width, buffer_height);
+        let (canvas, cairo_surface) = create_canvas(buffer_width, buffer_height);
+        self.cairo_surface = cairo_surface;
         self.cairo_ctx = cairo::Context::new(&self.cairo_surface).unwrap();
+        // The old surface and context are gone, so nothing draws into the
+        // old canvas anymore.
+        self.canvas = canvas;
+        // Everything is drawn in surface coordinates, like the input.
+        self.cairo_ctx.scale(self.scale, self.scale);
+        self.cairo_ctx.translate(0., self.ink_top as f64);
+        set_line(
+            &self.cairo_ctx,
+            self.config.ui.ink_color,
+            self.config.ui.line_width,
+        );
         self.redraw();
         self.display();
     }
 
+    /// A length in surface coordinates in buffer pixels.
+    fn to_buffer(&self, length: i32) -> i32 {
+        (length as f64 * self.scale).round() as i32
+    }
+
+    fn set_scale(&mut self, scale: f64) {
+        if scale == self.scale {
+            return;
+        }
+        info!("scale {}", scale);
+        self.scale = scale;
+        // The old cursors stay alive until the tools use the new ones, since
+        // destroying a cursor surface in use is an error.
+        let _old = self.cursors.take();
+        for (tool, serial) in self.inputs.tools() {
+            let is_eraser = self.tools.get(&tool.id()) == Some(&zwp_tablet_tool_v2::Type::Eraser);
+            self.set_tool_cursor(&tool, serial, is_eraser);
+        }
+        self.resize(self.width, self.height);
+    }
+
+    /// Asks for the fractional scale of the surface, if the compositor can
+    /// also scale buffers by it.
+    fn scale_surface(&mut self) {
+        if let Some((viewport, fractional_scale)) = self.viewport.take() {
+            viewport.destroy();
+            fractional_scale.destroy();
+        }
+        let (Some(viewporter), Some(manager)) = (&self.viewporter, &self.fractional_scale_manager)
+        else {
+            return;
+        };
+        self.viewport = Some((
+            viewporter.get_viewport(&self.surface, &self.wayland_qh, ()),
+            manager.get_fractional_scale(&self.surface, &self.wayland_qh, ()),
+        ));
+    }
+
     fn auto_resize(&mut self) {
-        if self.max_x > self.width as f64 * 0.8 {
+        // Panels have the size the compositor gives them.
+        if self.config.ui.mode != UiMode::Popup {
+            return;
+        }
+        let mut width = self.width;
+        while self.max_x > width as f64 * 0.8 {
+            width += self.config.ui.grow_step;
+        }
+        if width != self.width {
             info!("auto resize max_x: {}", self.max_x);
-            self.resize(self.width + 100, self.height);
+            self.resize(width, self.height);
         }
     }
 
     fn restore_size(&mut self) {
-        self.resize(self.original_width, self.original_height);
+        let (height, ink_top) = self.layout();
+        self.ink_top = ink_top;
+        self.resize(self.original_width, height);
+    }
+
+    /// The height of the writing area and where it starts on the surface,
+    /// given the caret. The compositor places the popup below the caret, or
+    /// above it if there is no room, so the candidates go on the side that
+    /// faces the caret.
+    fn layout(&self) -> (i32, i32) {
+        let Some(caret) = self.caret else {
+            return (self.original_height, 0);
+        };
+        let scale = self.config.ui.caret_scale;
+        let height = if scale > 0. {
+            self.original_height
+                .max((caret.height as f64 * scale).round() as i32)
+        } else {
+            self.original_height
+        };
+        let below_caret = caret.y + caret.height / 2 < 0;
+        let ink_top = if below_caret {
+            self.config.ui.candidate_height
+        } else {
+            0
+        };
+        (height, ink_top)
+    }
+
+    fn on_caret_moved(&mut self, caret: Caret) {
+        if self.caret == Some(caret) {
+            return;
+        }
+        self.caret = Some(caret);
+        // Strokes are kept where they are until they are committed.
+        if self.strokes.is_empty()
+            && !self.inputs.is_drawing()
+            && self.layout() != (self.height, self.ink_top)
+        {
+            info!("layout for caret {caret:?}: {:?}", self.layout());
+            self.restore_size();
+        }
     }
 
     fn enter_input(&mut self) {
-        self.input_method.commit_string(self.preedit_text.clone());
+        self.enter_input_with("");
+    }
+
+    /// Commits the preedit text followed by `suffix`, once the last stroke
+    /// has been recognized.
+    fn enter_input_with(&mut self, suffix: &str) {
+        if self.recognition_pending {
+            trace!("commit once the ink is recognized");
+            self.pending_commit
+                .get_or_insert_with(String::new)
+                .push_str(suffix);
+            return;
+        }
+        self.input_method
+            .commit_string(format!("{}{suffix}", self.preedit_text));
         self.input_method.commit(self.input_method_serial);
+        self.reset_ink();
+        info!("enter input");
+    }
+
+    /// Deletes the character before the cursor in the focused text field.
+    fn delete_before_cursor(&mut self) {
+        let length = self
+            .surrounding_text
+            .as_ref()
+            .map_or(1, SurroundingText::last_char_len);
+        self.input_method.delete_surrounding_text(length as u32, 0);
+        self.input_method.commit(self.input_method_serial);
+        info!("delete before cursor");
+    }
+
+    fn clear(&mut self) {
+        self.input_method.set_preedit_string(String::new(), 0, 0);
+        self.input_method.commit(self.input_method_serial);
+        self.reset_ink();
+        info!("clear");
+    }
+
+    /// Another input method is running on the seat, so this one is inert.
+    fn on_unavailable(&mut self) {
+        if !self.config.input_method.wait {
+            error!("another input method is already running, exiting (start with --wait to wait for it to exit)");
+            self.exit_status = Some(EXIT_UNAVAILABLE);
+            return;
+        }
+        let interval = self.config.input_method.retry_interval;
+        warn!("another input method is already running, trying again every {interval}s");
+        self.input_method_retry = Some(Instant::now() + Duration::from_secs(interval));
+    }
+
+    /// Replaces the unavailable input method with a new one, which is either
+    /// available or unavailable again.
+    fn retry_input_method(&mut self) {
+        trace!("retry input method");
+        self.input_method_retry = None;
+        if let Some(grab) = self.keyboard_grab.take() {
+            grab.release();
+        }
+        if let Some(popup) = self.popup.take() {
+            popup.destroy();
+        }
+        self.input_method.destroy();
+        self.input_method =
+            self.input_method_manager
+                .get_input_method(&self.seat, &self.wayland_qh, ());
+        if self.config.ui.mode == UiMode::Popup {
+            self.popup = Some(self.input_method.get_input_popup_surface(
+                &self.surface,
+                &self.wayland_qh,
+                (),
+            ));
+        }
+        self.input_method_serial = 0;
+        self.pending = PendingState::default();
+        self.active = false;
+    }
+
+    /// Applies the state received since the last `done` event.
+    fn apply_pending(&mut self) {
+        let pending = std::mem::take(&mut self.pending);
+        if let Some(active) = pending.active {
+            // Whatever was written belongs to the text field that had the
+            // focus before, so it is dropped rather than committed.
+            self.reset_ink();
+            self.surrounding_text = None;
+            self.mode = Mode::default();
+            self.active = active;
+            if active && self.enabled {
+                self.show();
+            } else if !active {
+                self.hide();
+            }
+        }
+        if let Some(surrounding_text) = pending.surrounding_text {
+            self.surrounding_text = Some(surrounding_text);
+        }
+        if let Some(mode) = pending.mode {
+            self.mode = mode;
+        }
+    }
+
+    fn reset_ink(&mut self) {
+        self.cancel_recognition();
+        self.pending_commit = None;
+        self.history.clear();
+        // A stroke being drawn is gone as well, so the pen, finger or mouse
+        // has to be lifted before it writes again.
+        self.inputs.ignore_all();
         self.strokes.clear();
+        self.erased.clear();
         self.preedit_text.clear();
+        self.candidates.clear();
         self.restore_size();
-        info!("enter input");
+        self.update_keyboard_grab();
     }
 
-    fn undo(&mut self) {
-        self.strokes.pop();
+    fn on_recognized(&mut self, candidates: Vec<Candidate>) {
+        for c in &candidates {
+            trace!(
+                "candidate {:?} score {:?}",
+                self.mode.loggable(&c.text),
+                c.score
+            );
+        }
+        let candidates = candidates
+            .into_iter()
+            .map(|c| Candidate {
+                text: self.mode.normalize(&c.text),
+                ..c
+            })
+            .filter(|c| self.mode.accepts(&c.text))
+            .collect();
+        self.candidates.set(candidates);
+        self.update_preedit();
+        self.draw_candidates();
+        self.display();
+        self.finish_commit();
+    }
+
+    /// Carries out a commit that was waiting for the recognition.
+    fn finish_commit(&mut self) {
+        if let Some(suffix) = self.pending_commit.take() {
+            self.enter_input_with(&suffix);
+        }
+    }
+
+    /// Returns false if there is no such candidate.
+    fn select_candidate(&mut self, index: usize) -> bool {
+        let from = self.candidates.selected();
+        if !self.show_candidate(index) {
+            return false;
+        }
+        if from != index {
+            self.history.push(Edit::SelectCandidate { from, to: index });
+        }
+        true
+    }
+
+    fn show_candidate(&mut self, index: usize) -> bool {
+        if !self.candidates.select(index) {
+            info!("no candidate #{}", index + 1);
+            return false;
+        }
+        info!("select candidate #{}", index + 1);
+        self.update_preedit();
+        self.draw_candidates();
+        self.display();
+        true
+    }
+
+    fn update_preedit(&mut self) {
+        let text = self.candidates.selected_text().unwrap_or_default();
+        self.preedit_text = match &self.surrounding_text {
+            Some(surrounding) => text::fit(
+                text,
+                surrounding.before_cursor(),
+                &self.config.text,
+                self.mode,
+            ),
+            None => text.to_string(),
+        };
+        info!("preedit text: {:?}", self.mode.loggable(&self.preedit_text));
+        // Sensitive text only shows up once committed.
+        let preedit = if self.mode.sensitive {
+            String::new()
+        } else {
+            self.preedit_text.clone()
+        };
+        self.input_method.set_preedit_string(preedit, 0, 0);
+        self.input_method.commit(self.input_method_serial);
+    }
+
+    /// Returns false if there is nothing to undo.
+    fn undo(&mut self) -> bool {
+        match self.history.undo(&mut self.strokes) {
+            Some(&Edit::SelectCandidate { to, .. }) => {
+                self.show_candidate(to);
+            }
+            Some(Edit::Strokes { .. }) => self.on_strokes_changed(),
+            None => {
+                info!("nothing to undo");
+                return false;
+            }
+        }
+        info!("undo");
+        true
+    }
+
+    /// Returns false if there is nothing to redo.
+    fn redo(&mut self) -> bool {
+        match self.history.redo(&mut self.strokes) {
+            Some(&Edit::SelectCandidate { to, .. }) => {
+                self.show_candidate(to);
+            }
+            Some(Edit::Strokes { .. }) => self.on_strokes_changed(),
+            None => {
+                info!("nothing to redo");
+                return false;
+            }
+        }
+        info!("redo");
+        true
+    }
+
+    /// Brings the surface, the recognition and the keyboard grab up to date
+    /// after strokes have been added or removed other than by drawing.
+    fn on_strokes_changed(&mut self) {
+        // Indices of a partial erasure no longer match.
+        self.erased.clear();
+        self.max_x = self
+            .strokes
+            .iter()
+            .flat_map(|s| &s.points)
+            .fold(0., |max, p| p.x.max(max));
+        self.auto_resize();
         self.redraw();
-        self.recognize();
-        info!("undo stroke");
+        if self.strokes.is_empty() {
+            // The keyboard is released without ink, so undo and redo keys
+            // would not reach htrime anymore.
+            self.history.clear();
+            self.candidates.clear();
+            self.update_preedit();
+            self.draw_candidates();
+            self.display();
+        } else {
+            self.recognize();
+        }
+        self.update_keyboard_grab();
     }
 }
 
-fn set_line(ctx: &cairo::Context) {
+fn set_line(ctx: &cairo::Context, color: Color, line_width: f64) {
     ctx.set_line_cap(cairo::LineCap::Round);
     ctx.set_line_join(cairo::LineJoin::Round);
-    ctx.set_source_rgba(0., 0., 0., 1.);
-    ctx.set_line_width(3.);
+    color.set_source(ctx);
+    ctx.set_line_width(line_width);
 }
@@ -1,13 +1,310 @@
-use std::process::{Child, Command, Stdio};
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::fs::FileExt;
+use std::process::{Child, ChildStdout, Command, Stdio};
 
-pub fn run() -> Child {
-    let mut command = Command::new("python");
+use log::{info, trace, warn};
+
+use crate::config::RecognizerConfig;
+use crate::{set_nonblocking, Stroke, NAME};
+
+mod engine;
+
+pub type RequestId = u32;
+
+/// The ink to be recognized, both as strokes and as the rendered content of
+/// the writing surface.
+pub struct Ink<'a> {
+    /// The size of the writing area in the coordinates of `strokes`.
+    pub width: i32,
+    pub height: i32,
+    pub image: Image<'a>,
+    pub strokes: &'a [Stroke],
+    /// The text before the cursor, possibly empty.
+    pub context: &'a str,
+    /// The characters the result may contain, any if `None`.
+    pub alphabet: Option<&'a str>,
+    /// Whether results must be kept out of the log.
+    pub sensitive: bool,
+}
+
+/// Pixels in Cairo's ARGB32 format, at the scale of the output, so possibly
+/// larger than the writing area.
+pub struct Image<'a> {
+    pub width: i32,
+    pub height: i32,
+    pub stride: i32,
+    pub data: &'a [u8],
+}
+
+pub struct Candidate {
+    pub text: String,
+    pub score: Option<f64>,
+}
+
+pub enum Event {
+    /// Candidates ranked from best to worst.
+    Recognized {
+        id: RequestId,
+        candidates: Vec<Candidate>,
+    },
+    Error {
+        id: Option<RequestId>,
+        message: String,
+    },
+}
+
+pub trait Recognizer {
+    /// The file descriptor to wait on for results.
+    fn fd(&self) -> RawFd;
+
+    fn submit(&mut self, id: RequestId, ink: &Ink) -> io::Result<()>;
+
+    /// Tells the recognizer that the result of `id` is no longer needed.
+    fn cancel(&mut self, _id: RequestId) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Reads the results that are available without blocking. Returns
+    /// `Ok(None)` once the recognizer has gone away.
+    fn receive(&mut self) -> io::Result<Option<Vec<Event>>>;
+
+    fn shutdown(&mut self);
+}
+
+pub const RECOGNIZERS: &[&str] = &["engine", "simplehtr"];
+
+pub fn spawn(config: &RecognizerConfig) -> io::Result<Box<dyn Recognizer>> {
+    match config.name.as_str() {
+        "engine" => Ok(Box::new(engine::Engine::spawn(config)?)),
+        "simplehtr" => Ok(Box::new(SimpleHtr::spawn(config)?)),
+        name => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "unknown recognizer {name:?}, available: {}",
+                RECOGNIZERS.join(", ")
+            ),
+        )),
+    }
+}
+
+/// Buffers the output of a child process and splits it into lines.
+struct LineReader {
+    stdout: ChildStdout,
+    buffer: Vec<u8>,
+}
+
+impl LineReader {
+    fn new(stdout: ChildStdout) -> io::Result<Self> {
+        set_nonblocking(&stdout, true)?;
+        Ok(Self {
+            stdout,
+            buffer: vec![],
+        })
+    }
+
+    fn read_lines(&mut self) -> io::Result<Option<Vec<String>>> {
+        let mut chunk = [0u8; 4096];
+        let mut eof = false;
+        loop {
+            match self.stdout.read(&mut chunk) {
+                Ok(0) => {
+                    eof = true;
+                    break;
+                }
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        let mut lines = vec![];
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            lines.push(line.trim_end_matches(['\n', '\r']).to_string());
+        }
+        if !self.buffer.is_empty() {
+            trace!(
+                "incomplete line {:?}",
+                String::from_utf8_lossy(&self.buffer)
+            );
+        }
+        if eof && lines.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(lines))
+    }
+}
+
+fn command(config: &RecognizerConfig) -> Command {
+    info!("starting recognizer {:?}", config.command);
+    let mut command = Command::new(&config.command[0]);
+    command.args(&config.command[1..]);
+    if let Some(dir) = &config.working_directory {
+        command.current_dir(dir);
+    }
     command
-        .arg("adaptor.py")
-        .current_dir("/home/mike/repos/third-party/SimpleHTR/src")
         .stdin(Stdio::piped())
-        .stderr(Stdio::piped())
+        .stderr(Stdio::inherit())
         .stdout(Stdio::piped());
+    command
+}
+
+/// SimpleHTR driven through its `adaptor.py`, predating the protocol in
+/// `PROTOCOL.md`. The adaptor is told the size of the surface, reads the
+/// pixels out of the `/dev/shm/htrime` object by itself and answers with a
+/// single `recognized:` line per request.
+pub struct SimpleHtr {
+    child: Child,
+    reader: LineReader,
+    replies: Replies,
+    sensitive: bool,
+    /// The `/dev/shm/htrime` object, unlinked on shutdown.
+    image: File,
+}
+
+impl SimpleHtr {
+    pub fn spawn(config: &RecognizerConfig) -> io::Result<Self> {
+        let image = create_shm_object()?;
+        let mut child = command(config).spawn()?;
+        let reader = LineReader::new(child.stdout.take().unwrap())?;
+        Ok(Self {
+            child,
+            reader,
+            replies: Replies::default(),
+            sensitive: false,
+            image,
+        })
+    }
+}
+
+/// Matches the answers of the adaptor to requests. It answers every request
+/// in order, so each answer belongs to the oldest request not answered yet.
+#[derive(Default)]
+struct Replies {
+    pending: VecDeque<RequestId>,
+}
+
+impl Replies {
+    fn submitted(&mut self, id: RequestId) {
+        self.pending.push_back(id);
+    }
+
+    fn parse(&mut self, line: &str) -> Option<Event> {
+        let text = line.strip_prefix("recognized:")?;
+        let Some(id) = self.pending.pop_front() else {
+            warn!("answer without a request: {line:?}");
+            return None;
+        };
+        Some(Event::Recognized {
+            id,
+            candidates: vec![Candidate {
+                text: text.trim().to_string(),
+                score: None,
+            }],
+        })
+    }
+}
+
+fn shm_name() -> CString {
+    CString::new(format!("/{NAME}")).unwrap()
+}
+
+/// Creates the object the adaptor reads from, replacing one left behind by
+/// an earlier run.
+fn create_shm_object() -> io::Result<File> {
+    let fd = unsafe {
+        libc::shm_open(
+            shm_name().as_ptr(),
+            libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC,
+            0o600,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+impl Recognizer for SimpleHtr {
+    fn fd(&self) -> RawFd {
+        self.reader.stdout.as_raw_fd()
+    }
+
+    fn submit(&mut self, id: RequestId, ink: &Ink) -> io::Result<()> {
+        self.replies.submitted(id);
+        self.sensitive = ink.sensitive;
+        let image = &ink.image;
+        let size = (image.stride * image.height) as usize;
+        self.image.set_len(size as u64)?;
+        self.image.write_all_at(&image.data[..size], 0)?;
+        let stdin = self.child.stdin.as_mut().unwrap();
+        stdin.write_all(format!("{} {}\n", image.width, image.height).as_bytes())?;
+        stdin.flush()
+    }
+
+    fn receive(&mut self) -> io::Result<Option<Vec<Event>>> {
+        let Some(lines) = self.reader.read_lines()? else {
+            return Ok(None);
+        };
+        let events = lines
+            .into_iter()
+            .filter_map(|line| {
+                if !self.sensitive {
+                    trace!("recognition output: {}", line);
+                }
+                self.replies.parse(&line)
+            })
+            .collect();
+        Ok(Some(events))
+    }
+
+    fn shutdown(&mut self) {
+        // closing stdin tells the adaptor to quit
+        drop(self.child.stdin.take());
+        if let Err(e) = self.child.wait() {
+            warn!("failed to wait for SimpleHTR: {e}");
+        }
+        unsafe { libc::shm_unlink(shm_name().as_ptr()) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recognized(event: Option<Event>) -> (RequestId, String) {
+        match event {
+            Some(Event::Recognized { id, candidates }) => (id, candidates[0].text.clone()),
+            _ => panic!("expected a result"),
+        }
+    }
+
+    #[test]
+    fn answers_belong_to_requests_in_order() {
+        let mut replies = Replies::default();
+        replies.submitted(1);
+        replies.submitted(2);
+        assert_eq!(
+            recognized(replies.parse("recognized: hel")),
+            (1, String::from("hel"))
+        );
+        assert_eq!(
+            recognized(replies.parse("recognized: hello")),
+            (2, String::from("hello"))
+        );
+    }
 
-    command.spawn().expect("failed to execute child")
+    #[test]
+    fn other_output_is_skipped() {
+        let mut replies = Replies::default();
+        replies.submitted(1);
+        assert!(replies.parse("loading model").is_none());
+        assert_eq!(recognized(replies.parse("recognized: a")).0, 1);
+        assert!(replies.parse("recognized: b").is_none());
+    }
 }
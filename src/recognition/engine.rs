@@ -0,0 +1,487 @@
+use std::io::{self, BufWriter, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::process::{Child, ChildStdin};
+use std::time::{Duration, Instant};
+
+use log::{info, trace, warn};
+
+use super::{command, Candidate, Event, Ink, LineReader, Recognizer, RequestId};
+use crate::config::{InkFormat, RecognizerConfig};
+
+/// The version of the protocol described in `PROTOCOL.md`.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Default, Debug)]
+pub struct Capabilities {
+    pub image: bool,
+    pub strokes: bool,
+    pub context: bool,
+    pub alphabet: bool,
+    pub devices: bool,
+    pub pen_axes: bool,
+}
+
+/// An engine speaking the htrime recognition protocol on its stdin and
+/// stdout.
+pub struct Engine {
+    child: Child,
+    /// Closed on shutdown.
+    stdin: Option<BufWriter<ChildStdin>>,
+    reader: LineReader,
+    capabilities: Capabilities,
+    format: InkFormat,
+    parser: Parser,
+    /// Whether the results of the last request must be kept out of the log.
+    sensitive: bool,
+}
+
+impl Engine {
+    pub fn spawn(config: &RecognizerConfig) -> io::Result<Self> {
+        let mut child = command(config).spawn()?;
+        let stdin = BufWriter::new(child.stdin.take().unwrap());
+        let reader = LineReader::new(child.stdout.take().unwrap())?;
+        let mut engine = Self {
+            child,
+            stdin: Some(stdin),
+            reader,
+            capabilities: Capabilities::default(),
+            format: config.input,
+            parser: Parser::default(),
+            sensitive: false,
+        };
+        engine.handshake()?;
+        Ok(engine)
+    }
+
+    /// Exchanges versions and capabilities, then settles on the ink format.
+    fn handshake(&mut self) -> io::Result<()> {
+        let stdin = self.stdin()?;
+        writeln!(stdin, "htrime {PROTOCOL_VERSION}")?;
+        stdin.flush()?;
+
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        let line = loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the engine did not answer the handshake",
+                ));
+            }
+            let mut poll_fds = [libc::pollfd {
+                fd: self.fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            }];
+            let ret = unsafe { libc::poll(poll_fds.as_mut_ptr(), 1, remaining.as_millis() as i32) };
+            if ret < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            match self.reader.read_lines()? {
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the engine exited during the handshake",
+                    ))
+                }
+                Some(lines) => {
+                    let mut lines = lines.into_iter();
+                    if let Some(line) = lines.next() {
+                        for line in lines {
+                            warn!("ignoring {line:?} sent before the handshake finished");
+                        }
+                        break line;
+                    }
+                }
+            }
+        };
+
+        self.capabilities = parse_handshake(&line)?;
+        info!("engine capabilities: {:?}", self.capabilities);
+        self.format = match self.format {
+            InkFormat::Auto if self.capabilities.strokes => InkFormat::Strokes,
+            InkFormat::Auto if self.capabilities.image => InkFormat::Image,
+            InkFormat::Image if self.capabilities.image => InkFormat::Image,
+            InkFormat::Strokes if self.capabilities.strokes => InkFormat::Strokes,
+            format => {
+                return Err(protocol_error(format!(
+                    "the engine does not accept {format:?} input"
+                )))
+            }
+        };
+        info!("sending {:?} to the engine", self.format);
+        Ok(())
+    }
+
+    fn write_strokes(&mut self, id: RequestId, ink: &Ink) -> io::Result<()> {
+        let Capabilities {
+            devices, pen_axes, ..
+        } = self.capabilities;
+        let stdin = self.stdin()?;
+        writeln!(
+            stdin,
+            "strokes {id} {} {} {}",
+            ink.width,
+            ink.height,
+            ink.strokes.len()
+        )?;
+        for stroke in ink.strokes {
+            if devices {
+                writeln!(
+                    stdin,
+                    "stroke {} {}",
+                    stroke.points.len(),
+                    stroke.device.name()
+                )?;
+            } else {
+                writeln!(stdin, "stroke {}", stroke.points.len())?;
+            }
+            for point in &stroke.points {
+                write!(
+                    stdin,
+                    "{} {} {} {}",
+                    point.x,
+                    point.y,
+                    point.time,
+                    number(point.pressure.map(|p| p as f64 / 65535.))
+                )?;
+                if pen_axes {
+                    let (tilt_x, tilt_y) = point.tilt.unzip();
+                    write!(
+                        stdin,
+                        " {} {} {} {}",
+                        number(tilt_x),
+                        number(tilt_y),
+                        number(point.rotation),
+                        number(point.distance.map(|d| d as f64 / 65535.))
+                    )?;
+                }
+                writeln!(stdin)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_image(&mut self, id: RequestId, ink: &Ink) -> io::Result<()> {
+        let image = &ink.image;
+        let stdin = self.stdin()?;
+        writeln!(stdin, "image {id} {} {}", image.width, image.height)?;
+        let mut row = Vec::with_capacity(image.width as usize);
+        for y in 0..image.height as usize {
+            let start = y * image.stride as usize;
+            let pixels = &image.data[start..start + image.width as usize * 4];
+            row.clear();
+            row.extend(pixels.chunks_exact(4).map(|p| {
+                let argb = u32::from_ne_bytes([p[0], p[1], p[2], p[3]]);
+                let r = (argb >> 16) & 0xff;
+                let g = (argb >> 8) & 0xff;
+                let b = argb & 0xff;
+                ((r * 299 + g * 587 + b * 114) / 1000) as u8
+            }));
+            stdin.write_all(&row)?;
+        }
+        Ok(())
+    }
+
+    fn stdin(&mut self) -> io::Result<&mut BufWriter<ChildStdin>> {
+        self.stdin
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "the engine was shut down"))
+    }
+}
+
+/// Turns the lines of the engine into events.
+#[derive(Default)]
+struct Parser {
+    /// A `result` header whose candidate lines have not all arrived yet.
+    partial: Option<(RequestId, usize, Vec<Candidate>)>,
+}
+
+impl Parser {
+    fn parse(&mut self, line: &str) -> Result<Option<Event>, String> {
+        if let Some((id, count, mut candidates)) = self.partial.take() {
+            let (score, text) = line
+                .split_once(' ')
+                .ok_or(format!("malformed candidate {line:?}"))?;
+            let score = match score {
+                "-" => None,
+                score => Some(
+                    score
+                        .parse()
+                        .map_err(|_| format!("malformed score in {line:?}"))?,
+                ),
+            };
+            candidates.push(Candidate {
+                text: text.to_string(),
+                score,
+            });
+            if candidates.len() < count {
+                self.partial = Some((id, count, candidates));
+                return Ok(None);
+            }
+            return Ok(Some(Event::Recognized { id, candidates }));
+        }
+
+        let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+        match command {
+            "result" => {
+                let mut args = args.split_whitespace();
+                let id = parse_id(args.next())?.ok_or("result without id")?;
+                let count: usize = args
+                    .next()
+                    .and_then(|c| c.parse().ok())
+                    .ok_or(format!("malformed result {line:?}"))?;
+                if count == 0 {
+                    return Ok(Some(Event::Recognized {
+                        id,
+                        candidates: vec![],
+                    }));
+                }
+                self.partial = Some((id, count, Vec::with_capacity(count)));
+                Ok(None)
+            }
+            "error" => {
+                let (id, message) = args.split_once(' ').unwrap_or((args, ""));
+                Ok(Some(Event::Error {
+                    id: parse_id(Some(id))?,
+                    message: message.to_string(),
+                }))
+            }
+            _ => Err(format!("unknown message {line:?}")),
+        }
+    }
+}
+
+/// Reads the capabilities from the engine's answer to the handshake.
+fn parse_handshake(line: &str) -> io::Result<Capabilities> {
+    let mut words = line.split_whitespace();
+    if words.next() != Some("engine") {
+        return Err(protocol_error(format!("unexpected handshake {line:?}")));
+    }
+    let version: u32 = words
+        .next()
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| protocol_error(format!("no version in handshake {line:?}")))?;
+    if version != PROTOCOL_VERSION {
+        return Err(protocol_error(format!(
+            "the engine speaks version {version}, htrime speaks {PROTOCOL_VERSION}"
+        )));
+    }
+    let mut capabilities = Capabilities::default();
+    for capability in words {
+        match capability {
+            "image" => capabilities.image = true,
+            "strokes" => capabilities.strokes = true,
+            "context" => capabilities.context = true,
+            "alphabet" => capabilities.alphabet = true,
+            "devices" => capabilities.devices = true,
+            "pen-axes" => capabilities.pen_axes = true,
+            _ => info!("ignoring unknown capability {capability:?}"),
+        }
+    }
+    Ok(capabilities)
+}
+
+fn parse_id(id: Option<&str>) -> Result<Option<RequestId>, String> {
+    match id {
+        Some("-") => Ok(None),
+        Some(id) => id
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("malformed request id {id:?}")),
+        None => Err("missing request id".to_string()),
+    }
+}
+
+fn protocol_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl Recognizer for Engine {
+    fn fd(&self) -> RawFd {
+        self.reader.stdout.as_raw_fd()
+    }
+
+    fn submit(&mut self, id: RequestId, ink: &Ink) -> io::Result<()> {
+        trace!("submit request {id}");
+        let (context, alphabet) = (self.capabilities.context, self.capabilities.alphabet);
+        let stdin = self.stdin()?;
+        if context {
+            writeln!(stdin, "context {id} {}", ink.context.len())?;
+            stdin.write_all(ink.context.as_bytes())?;
+        }
+        if let (true, Some(alphabet)) = (alphabet, ink.alphabet) {
+            writeln!(stdin, "alphabet {id} {}", alphabet.len())?;
+            stdin.write_all(alphabet.as_bytes())?;
+        }
+        self.sensitive = ink.sensitive;
+        match self.format {
+            InkFormat::Strokes => self.write_strokes(id, ink)?,
+            _ => self.write_image(id, ink)?,
+        }
+        self.stdin()?.flush()
+    }
+
+    fn cancel(&mut self, id: RequestId) -> io::Result<()> {
+        let stdin = self.stdin()?;
+        writeln!(stdin, "cancel {id}")?;
+        stdin.flush()
+    }
+
+    fn receive(&mut self) -> io::Result<Option<Vec<Event>>> {
+        let Some(lines) = self.reader.read_lines()? else {
+            return Ok(None);
+        };
+        let mut events = vec![];
+        for line in lines {
+            if !self.sensitive {
+                trace!("engine: {line}");
+            }
+            match self.parser.parse(&line) {
+                Ok(Some(event)) => events.push(event),
+                Ok(None) => {}
+                Err(e) => warn!("protocol error: {e}"),
+            }
+        }
+        Ok(Some(events))
+    }
+
+    fn shutdown(&mut self) {
+        let Some(mut stdin) = self.stdin.take() else {
+            return;
+        };
+        if writeln!(stdin, "quit").and_then(|_| stdin.flush()).is_err() {
+            trace!("the engine has already closed its stdin");
+        }
+        // Engines that read until the end of their input stop here.
+        drop(stdin);
+        if let Err(e) = self.child.wait() {
+            warn!("failed to wait for the engine: {e}");
+        }
+    }
+}
+
+/// A value as a protocol field, `-` if it is missing.
+fn number(value: Option<f64>) -> String {
+    value.map_or(String::from("-"), |value| format!("{value:.4}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(parser: &mut Parser, lines: &[&str]) -> Vec<Result<Option<Event>, String>> {
+        lines.iter().map(|line| parser.parse(line)).collect()
+    }
+
+    fn candidates(event: Option<Event>) -> (RequestId, Vec<(Option<f64>, String)>) {
+        match event {
+            Some(Event::Recognized { id, candidates }) => (
+                id,
+                candidates.into_iter().map(|c| (c.score, c.text)).collect(),
+            ),
+            _ => panic!("expected a result"),
+        }
+    }
+
+    #[test]
+    fn empty_result() {
+        let mut parser = Parser::default();
+        let event = parser.parse("result 3 0").unwrap();
+        assert_eq!(candidates(event), (3, vec![]));
+    }
+
+    #[test]
+    fn result_with_candidates() {
+        let mut parser = Parser::default();
+        let events = parse_all(
+            &mut parser,
+            &["result 2 3", "0.91 hello", "0.05 hella", "- hallo"],
+        );
+        assert!(events[..3].iter().all(|e| matches!(e, Ok(None))));
+        let event = events.into_iter().last().unwrap().unwrap();
+        assert_eq!(
+            candidates(event),
+            (
+                2,
+                vec![
+                    (Some(0.91), String::from("hello")),
+                    (Some(0.05), String::from("hella")),
+                    (None, String::from("hallo")),
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn candidate_text_keeps_spaces() {
+        let mut parser = Parser::default();
+        assert!(matches!(parser.parse("result 1 1"), Ok(None)));
+        let event = parser.parse("0.5 hello  big world ").unwrap();
+        assert_eq!(
+            candidates(event).1,
+            vec![(Some(0.5), String::from("hello  big world "))]
+        );
+    }
+
+    #[test]
+    fn error_without_request() {
+        let mut parser = Parser::default();
+        match parser.parse("error - model not found") {
+            Ok(Some(Event::Error { id, message })) => {
+                assert_eq!(id, None);
+                assert_eq!(message, "model not found");
+            }
+            _ => panic!("expected an error"),
+        }
+        match parser.parse("error 4 out of memory") {
+            Ok(Some(Event::Error { id, .. })) => assert_eq!(id, Some(4)),
+            _ => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn malformed_candidate_drops_the_result() {
+        let mut parser = Parser::default();
+        let events = parse_all(
+            &mut parser,
+            &["result 1 3", "0.9 hello", "garbage", "0.1 hallo"],
+        );
+        assert!(matches!(events[1], Ok(None)));
+        assert!(events[2].is_err());
+        // The rest of the broken result is not taken for a new message.
+        assert!(events[3].is_err());
+        let event = parser.parse("result 2 0").unwrap();
+        assert_eq!(candidates(event), (2, vec![]));
+        assert!(parser.parse("result 3 1").is_ok());
+        assert!(parser.parse("high hello").is_err());
+    }
+
+    #[test]
+    fn unknown_message() {
+        let mut parser = Parser::default();
+        assert!(parser.parse("progress 1 50%").is_err());
+        assert!(parser.parse("result x 1").is_err());
+    }
+
+    #[test]
+    fn handshake_capabilities() {
+        let capabilities = parse_handshake("engine 1 strokes context future-thing").unwrap();
+        assert!(capabilities.strokes && capabilities.context);
+        assert!(!capabilities.image && !capabilities.alphabet);
+    }
+
+    #[test]
+    fn handshake_version_mismatch() {
+        let error = parse_handshake("engine 2 image").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(parse_handshake("engine image").is_err());
+        assert!(parse_handshake("htrime 1").is_err());
+    }
+}
@@ -0,0 +1,234 @@
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+use std::os::raw::c_void;
+use std::ptr::null_mut;
+
+use log::trace;
+use wayland_client::protocol::wl_buffer::WlBuffer;
+use wayland_client::protocol::wl_shm::{self, WlShm};
+use wayland_client::protocol::wl_shm_pool::WlShmPool;
+use wayland_client::QueueHandle;
+
+use crate::{State, NAME};
+
+/// A `wl_shm_pool` on an anonymous file mapped into memory, grown as the
+/// surface grows.
+pub struct Pool {
+    pool: WlShmPool,
+    fd: OwnedFd,
+    size: usize,
+    data: *mut c_void,
+}
+
+impl Pool {
+    pub fn new(shm: &WlShm, size: usize, qh: &QueueHandle<State>) -> io::Result<Self> {
+        let name = CString::new(NAME).unwrap();
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        truncate(&fd, size)?;
+        let data = map(&fd, size)?;
+        let pool = shm.create_pool(fd.as_fd(), size as i32, qh, ());
+        Ok(Self {
+            pool,
+            fd,
+            size,
+            data,
+        })
+    }
+
+    /// Makes room for at least `size` bytes. Moves the memory, so anything
+    /// pointing into it has to be recreated.
+    pub fn ensure(&mut self, size: usize) -> io::Result<()> {
+        if size <= self.size {
+            return Ok(());
+        }
+        // Pools are sized in an i32 on the wire.
+        if size > i32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "surface too large",
+            ));
+        }
+        let size = size.max(self.size * 2).min(i32::MAX as usize);
+        trace!("grow shm pool to {size} bytes");
+        truncate(&self.fd, size)?;
+        let data = map(&self.fd, size)?;
+        unsafe { libc::munmap(self.data, self.size) };
+        self.data = data;
+        self.size = size;
+        self.pool.resize(size as i32);
+        Ok(())
+    }
+
+    pub fn data(&self) -> *mut u8 {
+        self.data as *mut u8
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.pool.destroy();
+        unsafe { libc::munmap(self.data, self.size) };
+    }
+}
+
+fn truncate(fd: &OwnedFd, size: usize) -> io::Result<()> {
+    loop {
+        if unsafe { libc::ftruncate(fd.as_raw_fd(), size as i64) } == 0 {
+            return Ok(());
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+fn map(fd: &OwnedFd, size: usize) -> io::Result<*mut c_void> {
+    let data = unsafe {
+        libc::mmap(
+            null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd.as_raw_fd(),
+            0,
+        )
+    };
+    if data == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(data)
+}
+
+/// Most buffers kept at a time. Two are enough unless the compositor holds on
+/// to buffers for long.
+const MAX_BUFFERS: usize = 3;
+
+/// Buffers of one size in a pool, each either free to draw into or held by
+/// the compositor until it sends `release`.
+pub struct Buffers {
+    pool: Pool,
+    slots: Vec<Slot>,
+    /// Busy buffers of an old size, destroyed once released. Their memory
+    /// stays reserved until then.
+    retired: Vec<Slot>,
+    width: i32,
+    height: i32,
+}
+
+struct Slot {
+    buffer: WlBuffer,
+    /// Where the buffer's memory is in the pool, in bytes.
+    offset: usize,
+    size: usize,
+    busy: bool,
+}
+
+impl Buffers {
+    pub fn new(shm: &WlShm, width: i32, height: i32, qh: &QueueHandle<State>) -> io::Result<Self> {
+        let pool = Pool::new(shm, 2 * 4 * width as usize * height as usize, qh)?;
+        Ok(Self {
+            pool,
+            slots: vec![],
+            retired: vec![],
+            width,
+            height,
+        })
+    }
+
+    /// Drops all buffers and makes new ones of the given size from now on.
+    pub fn resize(&mut self, width: i32, height: i32) {
+        for slot in self.slots.drain(..) {
+            if slot.busy {
+                self.retired.push(slot);
+            } else {
+                slot.buffer.destroy();
+            }
+        }
+        self.width = width;
+        self.height = height;
+    }
+
+    /// A buffer the compositor is done with, filled with `data`, or `None` if
+    /// all are in use. The buffer counts as busy until `release`.
+    pub fn next(&mut self, data: &[u8], qh: &QueueHandle<State>) -> io::Result<Option<&WlBuffer>> {
+        let stride = self.width * 4;
+        let size = stride as usize * self.height as usize;
+        let index = match self.slots.iter().position(|slot| !slot.busy) {
+            Some(index) => index,
+            None if self.slots.len() < MAX_BUFFERS => {
+                let index = self.slots.len();
+                let offset = self.free_offset(size);
+                self.pool.ensure(offset + size)?;
+                trace!("create buffer #{index} at {offset}");
+                let buffer = self.pool.pool.create_buffer(
+                    offset as i32,
+                    self.width,
+                    self.height,
+                    stride,
+                    wl_shm::Format::Argb8888,
+                    qh,
+                    (),
+                );
+                self.slots.push(Slot {
+                    buffer,
+                    offset,
+                    size,
+                    busy: false,
+                });
+                index
+            }
+            None => return Ok(None),
+        };
+        let slot = &mut self.slots[index];
+        let memory =
+            unsafe { std::slice::from_raw_parts_mut(self.pool.data().add(slot.offset), size) };
+        memory.copy_from_slice(&data[..size]);
+        slot.busy = true;
+        Ok(Some(&slot.buffer))
+    }
+
+    /// The start of the first gap of `size` bytes between the buffers in
+    /// use, retired ones included, which may be past the end of the pool.
+    fn free_offset(&self, size: usize) -> usize {
+        let mut used: Vec<(usize, usize)> = self
+            .slots
+            .iter()
+            .chain(&self.retired)
+            .map(|slot| (slot.offset, slot.offset + slot.size))
+            .collect();
+        used.sort_unstable();
+        let mut offset = 0;
+        for (start, end) in used {
+            if start >= offset + size {
+                break;
+            }
+            offset = offset.max(end);
+        }
+        offset
+    }
+
+    pub fn release(&mut self, buffer: &WlBuffer) {
+        if let Some(slot) = self.slots.iter_mut().find(|slot| &slot.buffer == buffer) {
+            slot.busy = false;
+        } else if let Some(index) = self.retired.iter().position(|slot| &slot.buffer == buffer) {
+            self.retired.swap_remove(index).buffer.destroy();
+        }
+    }
+}
+
+impl Drop for Buffers {
+    fn drop(&mut self) {
+        for slot in self.slots.drain(..) {
+            slot.buffer.destroy();
+        }
+        for slot in self.retired.drain(..) {
+            slot.buffer.destroy();
+        }
+    }
+}
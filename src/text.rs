@@ -0,0 +1,276 @@
+use wayland_protocols::wp::text_input::zv3::client::zwp_text_input_v3::{
+    ContentHint, ContentPurpose,
+};
+
+use crate::config::TextConfig;
+
+/// How much of the text before the cursor is passed to the recognizer, in
+/// bytes.
+const CONTEXT_LENGTH: usize = 200;
+
+/// The text around the cursor of the focused text field, as sent by the
+/// compositor. Positions are byte offsets into `text`.
+#[derive(Default)]
+pub struct SurroundingText {
+    pub text: String,
+    pub cursor: usize,
+    pub anchor: usize,
+}
+
+impl SurroundingText {
+    /// The text before the cursor, or before the selection if there is one,
+    /// since the selection is replaced by what gets committed.
+    pub fn before_cursor(&self) -> &str {
+        let start = self.cursor.min(self.anchor);
+        self.text.get(..start).unwrap_or_default()
+    }
+
+    /// The end of the text before the cursor, for the recognizer's language
+    /// model.
+    pub fn context(&self) -> &str {
+        let before = self.before_cursor();
+        let mut start = before.len().saturating_sub(CONTEXT_LENGTH);
+        while !before.is_char_boundary(start) {
+            start += 1;
+        }
+        &before[start..]
+    }
+
+    /// The length in bytes of the character before the cursor, 1 if it is
+    /// not known.
+    pub fn last_char_len(&self) -> usize {
+        self.before_cursor()
+            .chars()
+            .next_back()
+            .map_or(1, char::len_utf8)
+    }
+}
+
+/// How to handle text for the focused text field, derived from its content
+/// type.
+#[derive(Clone, Copy, Debug)]
+pub struct Mode {
+    /// The characters the field takes, any if `None`.
+    pub alphabet: Option<&'static str>,
+    pub auto_space: bool,
+    pub auto_capitalize: bool,
+    /// The text must not be shown as preedit or written to the log.
+    pub sensitive: bool,
+}
+
+const DIGITS: &str = "0123456789";
+
+impl Default for Mode {
+    fn default() -> Self {
+        Self {
+            alphabet: None,
+            auto_space: true,
+            auto_capitalize: false,
+            sensitive: false,
+        }
+    }
+}
+
+impl Mode {
+    pub fn new(hint: ContentHint, purpose: ContentPurpose) -> Self {
+        let mut mode = Self {
+            auto_capitalize: hint.contains(ContentHint::AutoCapitalization),
+            ..Self::default()
+        };
+        match purpose {
+            ContentPurpose::Digits | ContentPurpose::Pin => mode.alphabet = Some(DIGITS),
+            ContentPurpose::Number => mode.alphabet = Some("0123456789.,-+"),
+            ContentPurpose::Phone => mode.alphabet = Some("0123456789+*#()- "),
+            ContentPurpose::Date | ContentPurpose::Time | ContentPurpose::Datetime => {
+                mode.alphabet = Some("0123456789./-: ")
+            }
+            ContentPurpose::Email | ContentPurpose::Url | ContentPurpose::Terminal => {
+                mode.auto_space = false;
+                mode.auto_capitalize = false;
+            }
+            _ => {}
+        }
+        if mode.alphabet.is_some() {
+            mode.auto_space = false;
+            mode.auto_capitalize = false;
+        }
+        if hint.intersects(ContentHint::Lowercase | ContentHint::Uppercase) {
+            mode.auto_capitalize = false;
+        }
+        mode.sensitive = matches!(purpose, ContentPurpose::Password | ContentPurpose::Pin)
+            || hint.intersects(ContentHint::HiddenText | ContentHint::SensitiveData);
+        mode
+    }
+
+    /// Whether `text` only has characters the field takes.
+    pub fn accepts(&self, text: &str) -> bool {
+        self.alphabet
+            .is_none_or(|alphabet| text.chars().all(|c| alphabet.contains(c)))
+    }
+
+    /// Turns letters that are easily written for digits into those digits,
+    /// if the field takes digits but no letters.
+    pub fn normalize(&self, text: &str) -> String {
+        match self.alphabet {
+            Some(alphabet) if alphabet.starts_with(DIGITS) => text
+                .chars()
+                .map(|c| match c {
+                    'o' | 'O' | 'D' => '0',
+                    'l' | 'I' | '|' => '1',
+                    'z' | 'Z' => '2',
+                    's' | 'S' => '5',
+                    'b' | 'G' => '6',
+                    'B' => '8',
+                    'g' | 'q' => '9',
+                    c => c,
+                })
+                .collect(),
+            _ => text.to_string(),
+        }
+    }
+
+    /// `text` for the log, hidden if the field is sensitive.
+    pub fn loggable<'a>(&self, text: &'a str) -> &'a str {
+        if self.sensitive {
+            "<hidden>"
+        } else {
+            text
+        }
+    }
+}
+
+/// Adjusts recognized text to what comes before it: starts sentences with a
+/// capital letter and separates words with a space.
+pub fn fit(text: &str, before: &str, config: &TextConfig, mode: Mode) -> String {
+    let Some(first) = text.chars().next() else {
+        return String::new();
+    };
+    let mut result = String::with_capacity(text.len() + 1);
+    let last = before.chars().next_back();
+    if config.auto_space
+        && mode.auto_space
+        && first.is_alphanumeric()
+        && last.is_some_and(|c| !c.is_whitespace() && !"([{\"'/-".contains(c))
+        // More digits of a number.
+        && !(first.is_ascii_digit() && last.is_some_and(|c| c.is_ascii_digit()))
+    {
+        result.push(' ');
+    }
+    if config.auto_capitalize && mode.auto_capitalize && starts_sentence(before) {
+        result.extend(first.to_uppercase());
+        result.push_str(&text[first.len_utf8()..]);
+    } else {
+        result.push_str(text);
+    }
+    result
+}
+
+fn starts_sentence(before: &str) -> bool {
+    let trimmed = before.trim_end();
+    trimmed.is_empty() || trimmed.ends_with(['.', '!', '?']) || before.ends_with('\n')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: TextConfig = TextConfig {
+        auto_space: true,
+        auto_capitalize: true,
+    };
+
+    fn prose() -> Mode {
+        Mode::new(ContentHint::AutoCapitalization, ContentPurpose::Normal)
+    }
+
+    #[test]
+    fn spaces_between_words() {
+        assert_eq!(fit("world", "hello", &CONFIG, prose()), " world");
+        assert_eq!(fit("world", "hello,", &CONFIG, prose()), " world");
+        assert_eq!(fit("world", "hello ", &CONFIG, prose()), "world");
+        assert_eq!(fit("world", "(", &CONFIG, prose()), "world");
+        assert_eq!(fit(",", "hello", &CONFIG, prose()), ",");
+    }
+
+    #[test]
+    fn no_space_within_numbers() {
+        assert_eq!(fit("23", "20", &CONFIG, prose()), "23");
+        assert_eq!(fit("23", "page", &CONFIG, prose()), " 23");
+        assert_eq!(fit("a", "20", &CONFIG, prose()), " a");
+    }
+
+    #[test]
+    fn capitalizes_sentences() {
+        assert_eq!(fit("hello", "", &CONFIG, prose()), "Hello");
+        assert_eq!(fit("hello", "Hi.", &CONFIG, prose()), " Hello");
+        assert_eq!(fit("hello", "Hi!\n", &CONFIG, prose()), "Hello");
+        assert_eq!(fit("hello", "Hi,", &CONFIG, prose()), " hello");
+        assert_eq!(fit("élan", "", &CONFIG, prose()), "Élan");
+    }
+
+    #[test]
+    fn config_turns_fitting_off() {
+        let config = TextConfig {
+            auto_space: false,
+            auto_capitalize: false,
+        };
+        assert_eq!(fit("world", "Hi.", &config, prose()), "world");
+    }
+
+    #[test]
+    fn capitalizes_only_when_asked() {
+        let mode = Mode::new(ContentHint::None, ContentPurpose::Normal);
+        assert_eq!(fit("hello", "", &CONFIG, mode), "hello");
+        let mode = Mode::new(
+            ContentHint::AutoCapitalization | ContentHint::Lowercase,
+            ContentPurpose::Normal,
+        );
+        assert_eq!(fit("hello", "", &CONFIG, mode), "hello");
+        assert!(!Mode::default().auto_capitalize);
+    }
+
+    #[test]
+    fn digit_fields() {
+        for purpose in [
+            ContentPurpose::Digits,
+            ContentPurpose::Number,
+            ContentPurpose::Phone,
+        ] {
+            let mode = Mode::new(ContentHint::AutoCapitalization, purpose);
+            assert!(mode.accepts("123"));
+            assert!(!mode.accepts("12a"));
+            assert_eq!(fit("23", "1 ", &CONFIG, mode), "23");
+            assert_eq!(fit("5", "x", &CONFIG, mode), "5");
+        }
+        let mode = Mode::new(ContentHint::None, ContentPurpose::Digits);
+        assert_eq!(mode.normalize("lO5"), "105");
+        assert!(!mode.sensitive);
+    }
+
+    #[test]
+    fn text_fields_take_anything() {
+        let mode = prose();
+        assert!(mode.accepts("hello, world"));
+        assert_eq!(mode.normalize("lO5"), "lO5");
+    }
+
+    #[test]
+    fn addresses_are_not_spaced() {
+        for purpose in [ContentPurpose::Email, ContentPurpose::Url] {
+            let mode = Mode::new(ContentHint::AutoCapitalization, purpose);
+            assert_eq!(fit("example", "me@", &CONFIG, mode), "example");
+            assert_eq!(fit("com", "example.", &CONFIG, mode), "com");
+        }
+    }
+
+    #[test]
+    fn sensitive_fields() {
+        let mode = Mode::new(ContentHint::None, ContentPurpose::Password);
+        assert!(mode.sensitive);
+        assert_eq!(mode.loggable("secret"), "<hidden>");
+        let mode = Mode::new(ContentHint::SensitiveData, ContentPurpose::Normal);
+        assert!(mode.sensitive);
+        assert!(!prose().sensitive);
+        assert_eq!(prose().loggable("hello"), "hello");
+    }
+}